@@ -34,6 +34,9 @@ async function handle_core_paired(core) {
         librespot_http_url = core.registration.extension_host;
         host = new Host({
             log_dir,
+            cache_dir: path.join(log_dir, 'cache'), // Spotify credentials per zone
+            audio_cache_dir:  path.join(log_dir, 'cache', 'audio'),
+            audio_cache_size: 1024 * 1024 * 1024, // 1GB
             base_url: librespot_http_url === "127.0.0.1" ? librespot_http_url : "0.0.0.0", // Host to listen on locally
             listen_port: null,
             callbacks: {
@@ -45,17 +48,25 @@ async function handle_core_paired(core) {
                 Clear:     spotify_tells_us_to_clear,
                 VolumeSet: spotify_tells_us_to_set_volume,
                 Stop:      spotify_tells_us_to_stop,
+                ZoneError: spotify_tells_us_about_zone_error,
+                Reconnected: spotify_tells_us_zone_reconnected,
             }
         });
     }
-    await host.start();
-    librespot_http_port = host.port();
+    try {
+        const { port } = await host.start();
+        librespot_http_port = port;
+    } catch (e) {
+        logger.error(`Host failed to start: ${e.message}`);
+        svc_status.set_status(`Could not start: ${e.message}`, true);
+        return;
+    }
     logger.info(`Host started and listening at ${librespot_http_url}:${librespot_http_port}`);
     core.services.RoonApiTransport.subscribe_zones((response, msg) => {
         if (response == 'Subscribed') {
             msg.zones.forEach(e => { zones[e.zone_id] = e; });
             msg.zones.forEach(z => {
-                host.send_roon_message({
+                send_roon_message({
                     type: 'EnableZone',
                     name: 'Roon - ' + z.display_name,
                     id:   z.zone_id
@@ -68,7 +79,7 @@ async function handle_core_paired(core) {
 
             if(msg.zones_removed) {
                 msg.zones_removed.forEach(id => {
-                    host.send_roon_message({
+                    send_roon_message({
                         type: 'DisableZone',
                         id
                     });
@@ -76,7 +87,7 @@ async function handle_core_paired(core) {
             }
             if(msg.zones_added) {
                 msg.zones_added.forEach(z => {
-                    host.send_roon_message({
+                    send_roon_message({
                         type: 'EnableZone',
                         name: 'Roon - ' + z.display_name,
                         id:   z.zone_id,
@@ -91,7 +102,7 @@ async function handle_core_paired(core) {
                     let oldz = zones[z.zone_id];
                     if (oldz.display_name !== z.display_name) {
                         logger.info("Sending rename message from javascript");
-                        host.send_roon_message({
+                        send_roon_message({
                             type:   'RenameZone',
                             id:     z.zone_id,
                             name:   'Roon - ' + z.display_name
@@ -104,14 +115,14 @@ async function handle_core_paired(core) {
                             const newVolumeHandle = z.outputs[0].volume;
                             if (volumeHandle.is_muted != newVolumeHandle.is_muted) {
                                 if (newVolumeHandle.is_muted) {
-                                    host.send_roon_message({
+                                    send_roon_message({
                                         type:   'Volume',
                                         id:     z.zone_id,
                                         volume: 0
                                     });
                                 } else {
                                     const newVolume = Math.ceil(((newVolumeHandle.value - newVolumeHandle.min) / (newVolumeHandle.max - newVolumeHandle.min)) * 65535);
-                                    host.send_roon_message({
+                                    send_roon_message({
                                         type:   'Volume',
                                         id:     z.zone_id,
                                         volume: newVolume
@@ -120,7 +131,7 @@ async function handle_core_paired(core) {
                             } else if (volumeHandle.value != newVolumeHandle.value) {
                                 logger.info('CHANGING VOLUME');
                                 const newVolume = Math.ceil(((newVolumeHandle.value - newVolumeHandle.min) / (newVolumeHandle.max - newVolumeHandle.min)) * 65535);
-                                host.send_roon_message({
+                                send_roon_message({
                                     type:   'Volume',
                                     id:     z.zone_id,
                                     volume: newVolume
@@ -197,7 +208,7 @@ async function getOrCreateSession(zone_id) {
                     // Zone has volume
                     if (z && z.outputs.length == 1 && z.outputs[0].volume && z.outputs[0].volume.step) {
                         const volumeHandle = z.outputs[0].volume;
-                        host.send_roon_message({
+                        send_roon_message({
                             type:   'Volume',
                             id:     z.zone_id,
                             volume: Math.ceil((volumeHandle.value - volumeHandle.min) / (volumeHandle.max - volumeHandle.min) * 65535)
@@ -207,12 +218,12 @@ async function getOrCreateSession(zone_id) {
                     resolve(body.session_id);
                 } else if (msg == "TransportControl") {
                     if (body.control == "next")
-                        host.send_roon_message({
+                        send_roon_message({
                             type:        'NextTrack',
                             id:          zone_id,
                         });
                     else if (body.control == "previous")
-                        host.send_roon_message({
+                        send_roon_message({
                             type:        'PreviousTrack',
                             id:          zone_id,
                         });
@@ -236,14 +247,30 @@ async function getOrCreateSession(zone_id) {
     return await p;
 }
 
+// Malformed messages reject, log them instead of leaving the rejection unhandled
+function send_roon_message(msg) {
+    return host.send_roon_message(msg).catch(e => {
+        logger.error(`Spotify rejected ${msg.type} message: ${e.message}`);
+    });
+}
+
+function getCoverId(now_playing_info) {
+    // Prefer the largest image when sizes are known
+    const images = now_playing_info.images || [];
+    const largest = images.reduce((best, image) => (!best || image.width > best.width) ? image : best, null);
+    if (largest) return largest.file_id;
+    return (now_playing_info.covers || [])[0];
+}
+
 function getNowPlaying(now_playing_info) {
     let info = {
         is_seek_allowed:  true,
         is_pause_allowed: true,
-        image_url:        'https://i.scdn.co/image/' + now_playing_info.covers[0],
+        image_url:        'https://i.scdn.co/image/' + getCoverId(now_playing_info),
     }
 
-    if (now_playing_info.album_name || !now_playing_info.show_name) {
+    const is_episode = now_playing_info.media_type === 'episode' || (!now_playing_info.album_name && now_playing_info.show_name);
+    if (!is_episode) {
         info.one_line = {
             line1: `${now_playing_info.name} - ${now_playing_info.artists.join('/')}`,
         };
@@ -256,7 +283,7 @@ function getNowPlaying(now_playing_info) {
             line2: `${now_playing_info.artists.join(' / ')}`,
             line3: `${now_playing_info.album_name}`,
         };
-    } else if (now_playing_info.show_name) {
+    } else if (is_episode) {
         info.one_line = {
             line1: `${now_playing_info.name} - ${now_playing_info.show_name}`,
         };
@@ -267,7 +294,7 @@ function getNowPlaying(now_playing_info) {
         info.three_line = {
             line1: `${now_playing_info.name}`,
             line2: `${now_playing_info.show_name}`,
-            line3: ''
+            line3: [now_playing_info.publisher, now_playing_info.release_date].filter(x => x).join(' - ')
         };
     } else {
         info.one_line   = { line1: "Unknown" };
@@ -291,54 +318,64 @@ const inc = () => {
 
 const handle_play_slot_event = (event, body, slots, zone_id) => {
     if (event == "OnToNext") {
-        host.send_roon_message({
+        send_roon_message({
             type: 'OnToNext',
             id:   zone_id,
         });
     } else if (event == "Time") {
-        host.send_roon_message({
+        send_roon_message({
             type:        'Time',
             id:          zone_id,
             seek_position_ms: body.seek_position_ms || 0,
             track_id:         slots.play.track_id
         });
+    } else if (event == "Seeked") {
+        send_roon_message({
+            type:        'Seeked',
+            id:          zone_id,
+            seek_position_ms: body.seek_position_ms || 0,
+            track_id:         slots.play.track_id
+        });
     } else if (event == "Playing") {
-        host.send_roon_message({
+        send_roon_message({
             type:        'Playing',
             id:          zone_id,
         });
     } else if (event == "Paused") {
-        host.send_roon_message({
+        send_roon_message({
             type:        'Paused',
             id:          zone_id,
         });
     } else if (event == "Unpaused") {
-        host.send_roon_message({
+        send_roon_message({
             type:        'Unpaused',
             id:          zone_id,
         });
     } else if (event == "EndedNaturally") {
         if (slots.queue) {
-            host.send_roon_message({
+            send_roon_message({
                 type: 'OnToNext',
                 id:   zone_id,
             });
             zoneSlots[zone_id] = null;
         } else {
-            host.send_roon_message({
+            send_roon_message({
                 type:        'Stopped',
                 id:          zone_id,
             });
             zoneSlots[zone_id] = null;
         }
     } else if (event == "MediaError") {
-        host.send_roon_message({
-            type:        'Stopped',
+        // Rust retries the track once, then skips it
+        send_roon_message({
+            type:        'Error',
             id:          zone_id,
+            reason:      media_error_reason(body),
+            slot:        'play',
         });
         zoneSlots[zone_id] = null;
     } else if (event == "StoppedUser") {
-        host.send_roon_message({
+        send_roon_message({
             type:        'Stopped',
             id:          zone_id,
         });
@@ -349,15 +386,33 @@ const handle_play_slot_event = (event, body, slots, zone_id) => {
 const handle_queue_slot_event = (event, body, slots, zone_id) => {
     if (event == 'Playing') {
         slots.queue.playing = true;
-        host.send_roon_message({
+        send_roon_message({
             type:        'Playing',
             id:          zone_id,
         });
+    } else if (event == 'MediaError') {
+        slots.queue = null;
+        send_roon_message({
+            type:        'Error',
+            id:          zone_id,
+            reason:      media_error_reason(body),
+            slot:        'queue',
+        });
     } else {
         console.log('UNHANDLED QUEUE SLOT EVENT', 'id:', slots.queue.id, event);
     }
 }
 
+// Track or album gain from spotify's normalisation data, left out when the file had none
+const replay_gain_body = replay_gain => {
+    if (!replay_gain) return {};
+    return { replay_gain: { gain_db: replay_gain.gain_db, peak: replay_gain.peak } };
+}
+
+const media_error_reason = body => {
+    return (body && (body.error || body.message)) || 'MediaError';
+}
+
 
 
 async function spotify_tells_us_to_play({
@@ -366,6 +421,8 @@ async function spotify_tells_us_to_play({
     position_ms,
     play_request_id,
     preload_id,
+    stream_url,
+    replay_gain,
 }) {
 
     const slots = getSlots(zone_id);
@@ -389,9 +446,10 @@ async function spotify_tells_us_to_play({
         track_id: now_playing_info.track_id,
         type: "track",
         slot: "play",
-        media_url: `http://${librespot_http_url}:${librespot_http_port}/stream/${zone_id}/${now_playing_info.track_id}`,
+        media_url: `http://${librespot_http_url}:${librespot_http_port}${stream_url}`,
         seek_position_ms: position_ms,
-        info
+        info,
+        ...replay_gain_body(replay_gain)
     };
 
     slots.play = { ...play_body, id: _slot_id, play_request_id, preload_id };
@@ -409,7 +467,7 @@ async function spotify_tells_us_to_play({
     })
     
 }
-async function spotify_tells_us_to_preload({ zone_id, now_playing_info, preload_id }) {
+async function spotify_tells_us_to_preload({ zone_id, now_playing_info, preload_id, stream_url, replay_gain }) {
     const slots = getSlots(zone_id);
 
     logger.info('spotify told us to preload ' + zone_id);
@@ -420,9 +478,10 @@ async function spotify_tells_us_to_preload({ zone_id, now_playing_info, preload_
         track_id: now_playing_info.track_id,
         type: "track",
         slot: "queue",
-        media_url: `http://${librespot_http_url}:${librespot_http_port}/stream/${zone_id}/${now_playing_info.track_id}`,
+        media_url: `http://${librespot_http_url}:${librespot_http_port}${stream_url}`,
         seek_position_ms: 0,
-        info
+        info,
+        ...replay_gain_body(replay_gain)
     }
 
     let _slot_id = inc();
@@ -455,6 +514,10 @@ async function spotify_tells_us_to_preload({ zone_id, now_playing_info, preload_
 
 async function spotify_tells_us_to_clear({ zone_id, slots }) {
     logger.info({msg: 'Got clear from spotify', zone_id, slots });
+    const zoneSlots = getSlots(zone_id);
+    slots.forEach(slot => { zoneSlots[slot] = null; });
+    // Nothing was ever queued without a session
+    if (!sessions[zone_id]) return;
     const session_id = await getOrCreateSession(zone_id);
     global_core.services.RoonApiAudioInput.clear({ session_id, slots });
 }
@@ -514,3 +577,15 @@ function spotify_tells_us_to_set_volume({zone_id, volume}) {
         logger.info({msg: 'Got set volume from spotify, ignoring nothing in play slot', zone_id, volume});
     }
 }
+function spotify_tells_us_about_zone_error({ zone_id, kind, message, recoverable }) {
+    logger.error({msg: 'Got zone error from spotify', zone_id, kind, message, recoverable});
+    const zone = zones[zone_id];
+    const name = zone ? zone.display_name : zone_id;
+    svc_status.set_status(`${name}: ${message}${recoverable ? ', restarting zone' : ''}`, !recoverable);
+}
+function spotify_tells_us_zone_reconnected({ zone_id, attempt }) {
+    logger.info({msg: 'Zone reconnected to spotify', zone_id, attempt});
+    const zone = zones[zone_id];
+    const name = zone ? zone.display_name : zone_id;
+    svc_status.set_status(`${name}: reconnected to Spotify (attempt ${attempt})`, false);
+}
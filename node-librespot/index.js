@@ -6,7 +6,14 @@ class Host {
         this.cbs = {
             ...opts.callbacks
         }
-        this._ref = Librespot.init(opts.base_url, opts.listen_port, (e) => { this._SPOTIFY_EVENT(e) });
+        this._ref = Librespot.init(opts.base_url, opts.listen_port, (e) => { this._SPOTIFY_EVENT(e) },
+            opts.cache_dir,
+            opts.audio_cache_dir,
+            opts.audio_cache_size,
+            opts.stream_load_timeout_ms,
+            opts.status_clients
+        );
+        this._last_op = Promise.resolve();
     }
 
     _SPOTIFY_EVENT(e) {
@@ -21,24 +28,32 @@ class Host {
     url() {
         return Librespot.url.call(this._ref);
     }
+    audio_cache_stats() {
+        return Librespot.audio_cache_stats.call(this._ref);
+    }
+    // Rejects if the rust side doesn't answer within a few seconds
+    async zones() {
+        return JSON.parse(await Librespot.zones.call(this._ref));
+    }
+    async zone_state(zone_id) {
+        return JSON.parse(await Librespot.zone_state.call(this._ref, zone_id));
+    }
+    // Rejects when the message doesn't match a RoonMessage
     async send_roon_message(msg) {
-        return Librespot.send_roon_message.call(this._ref, JSON.stringify(msg));
+        return Librespot.send_roon_message.call(this._ref, msg);
     }
-    async start() {
-        while(this.doingstuff) {
-            await new Promise(r => setTimeout(r, 100));
-        }
-        this.doingstuff = true;
-        const res = await Librespot.start.call(this._ref);
+    // Both are idempotent, queueing them keeps pair/unpair cycles in order
+    _queue(op) {
+        const res = this._last_op.catch(() => {}).then(op);
+        this._last_op = res;
         return res;
     }
-    async stop() {
-        while(!this.doingstuff) {
-            await new Promise(r => setTimeout(r, 100));
-        }
-        const res = await Librespot.stop.call(this._ref);
-        this.doingstuff = false;
-        return res;
+    // Resolves with { url, port }, rejects if the server can't listen
+    start() {
+        return this._queue(() => Librespot.start.call(this._ref));
+    }
+    stop() {
+        return this._queue(() => Librespot.stop.call(this._ref));
     }
 }
 
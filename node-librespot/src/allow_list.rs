@@ -0,0 +1,120 @@
+use std::net::IpAddr;
+
+// Used when init doesn't pass a list, any client on the local network
+const DEFAULT_STATUS_CLIENTS: [&str; 5] = [
+    "10.0.0.0/8",
+    "172.16.0.0/12",
+    "192.168.0.0/16",
+    "fc00::/7",  // Unique local
+    "fe80::/10", // Link local
+];
+
+// Clients allowed to read zone status and metrics. Loopback is always allowed,
+// the rest is a list of addresses or networks like "192.168.1.0/24".
+#[derive(Debug, Clone)]
+pub struct ClientAllowList {
+    networks: Vec<(IpAddr, u8)>,
+}
+
+impl Default for ClientAllowList {
+    fn default() -> ClientAllowList {
+        ClientAllowList::parse(&DEFAULT_STATUS_CLIENTS).expect("Default status clients are valid")
+    }
+}
+
+impl ClientAllowList {
+    pub fn parse<S: AsRef<str>>(entries: &[S]) -> Result<ClientAllowList, String> {
+        let networks = entries.iter()
+            .map(|entry| parse_network(entry.as_ref().trim()))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(ClientAllowList { networks })
+    }
+
+    pub fn allows(&self, addr: IpAddr) -> bool {
+        let addr = canonical(addr);
+        addr.is_loopback() || self.networks.iter().any(|(network, prefix)| contains(*network, *prefix, addr))
+    }
+}
+
+fn parse_network(entry: &str) -> Result<(IpAddr, u8), String> {
+    let invalid = || format!("Invalid status client {}, expected an address or network", entry);
+    let (addr, prefix) = match entry.split_once('/') {
+        Some((addr, prefix)) => (addr, Some(prefix)),
+        None                 => (entry, None)
+    };
+    let addr = canonical(addr.parse::<IpAddr>().map_err(|_| invalid())?);
+    let max_prefix = if addr.is_ipv4() { 32 } else { 128 };
+    let prefix = match prefix {
+        Some(prefix) => prefix.parse::<u8>().ok().filter(|p| *p <= max_prefix).ok_or_else(invalid)?,
+        None         => max_prefix
+    };
+    Ok((addr, prefix))
+}
+
+// Dual stack sockets report IPv4 clients as ::ffff:a.b.c.d
+fn canonical(addr: IpAddr) -> IpAddr {
+    match addr {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(addr),
+        addr => addr
+    }
+}
+
+fn contains(network: IpAddr, prefix: u8, addr: IpAddr) -> bool {
+    match (network, addr) {
+        (IpAddr::V4(network), IpAddr::V4(addr)) => {
+            let mask = u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0);
+            u32::from(network) & mask == u32::from(addr) & mask
+        },
+        (IpAddr::V6(network), IpAddr::V6(addr)) => {
+            let mask = u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0);
+            u128::from(network) & mask == u128::from(addr) & mask
+        },
+        _ => false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(addr: &str) -> IpAddr {
+        addr.parse().unwrap()
+    }
+
+    #[test]
+    fn default_allows_local_networks_only() {
+        let allowed = ClientAllowList::default();
+        assert!(allowed.allows(ip("127.0.0.1")));
+        assert!(allowed.allows(ip("::1")));
+        assert!(allowed.allows(ip("192.168.1.20")));
+        assert!(allowed.allows(ip("10.1.2.3")));
+        assert!(allowed.allows(ip("172.31.255.1")));
+        assert!(allowed.allows(ip("::ffff:192.168.1.20")));
+        assert!(allowed.allows(ip("fe80::1")));
+        assert!(!allowed.allows(ip("172.32.0.1")));
+        assert!(!allowed.allows(ip("8.8.8.8")));
+        assert!(!allowed.allows(ip("2001:db8::1")));
+    }
+
+    #[test]
+    fn parses_addresses_and_networks() {
+        let allowed = ClientAllowList::parse(&["192.168.1.20", "2001:db8::/32", "0.0.0.0/0"]).unwrap();
+        assert!(allowed.allows(ip("8.8.8.8")));
+        assert!(allowed.allows(ip("2001:db8::1")));
+        assert!(!allowed.allows(ip("2001:db9::1")));
+
+        let allowed = ClientAllowList::parse(&["192.168.1.20"]).unwrap();
+        assert!(allowed.allows(ip("192.168.1.20")));
+        assert!(!allowed.allows(ip("192.168.1.21")));
+        // Loopback even when the list doesn't name it
+        assert!(allowed.allows(ip("127.0.0.1")));
+    }
+
+    #[test]
+    fn rejects_invalid_entries() {
+        assert!(ClientAllowList::parse(&["192.168.1.0/33"]).is_err());
+        assert!(ClientAllowList::parse(&["::/129"]).is_err());
+        assert!(ClientAllowList::parse(&["localhost"]).is_err());
+        assert!(ClientAllowList::parse(&["10.0.0.0/"]).is_err());
+    }
+}
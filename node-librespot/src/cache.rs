@@ -0,0 +1,200 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::SystemTime;
+use serde::{Serialize, Deserialize};
+
+use librespot::core::cache::Cache;
+
+static AUDIO_CACHE_HITS:   AtomicU64 = AtomicU64::new(0);
+static AUDIO_CACHE_MISSES: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug, Clone, Default)]
+pub struct CacheConfig {
+    pub location:       Option<PathBuf>, // credentials and volume, one directory per zone
+    pub audio_location: Option<PathBuf>, // encrypted audio files, shared by all zones
+    pub audio_limiter:  Option<Arc<AudioCacheLimiter>>, // shared by all zones
+}
+
+impl CacheConfig {
+    pub fn new(location: Option<PathBuf>, audio_location: Option<PathBuf>, audio_size_limit: Option<u64>) -> CacheConfig {
+        let audio_limiter = match (&audio_location, audio_size_limit) {
+            (Some(audio_location), Some(size_limit)) => Some(Arc::new(AudioCacheLimiter {
+                location: audio_location.clone(),
+                size_limit,
+                sweeping: Mutex::new(()),
+            })),
+            _ => None
+        };
+        CacheConfig { location, audio_location, audio_limiter }
+    }
+
+    // librespot's size limit is per Cache, so zone caches get none and the shared
+    // limiter keeps the audio directory within the limit for all of them
+    pub fn zone_cache(&self, device_id: &str) -> Option<Cache> {
+        if self.location.is_none() && self.audio_location.is_none() {
+            return None;
+        }
+        let location = self.location.as_ref().map(|l| l.join(device_id));
+        match Cache::new(
+            location.clone(),
+            location,
+            self.audio_location.clone(),
+            None
+        ) {
+            Ok(cache) => Some(cache),
+            Err(err) => {
+                warn!("Could not initialize cache: {}.", err);
+                None
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct AudioCacheLimiter {
+    location:   PathBuf,
+    size_limit: u64, // bytes
+    sweeping:   Mutex<()>,
+}
+
+impl AudioCacheLimiter {
+    // Evicts least recently used files until a file of `incoming` bytes fits the limit.
+    // Called before a download starts, librespot writes the file once it is complete.
+    pub fn make_room(&self, incoming: u64) {
+        // Zones sweep one at a time so every download's size is accounted for
+        let _sweeping = self.sweeping.lock().unwrap();
+        let available = self.size_limit.saturating_sub(incoming);
+        let mut files = vec![];
+        collect_files(&self.location, &mut files);
+        let mut total: u64 = files.iter().map(|(_, size, _)| size).sum();
+        if total <= available {
+            return;
+        }
+        files.sort_by_key(|(_, _, used)| *used);
+        for (path, size, _) in files {
+            if total <= available {
+                break;
+            }
+            match fs::remove_file(&path) {
+                Ok(()) => total -= size,
+                Err(err) => warn!("Could not evict {} from the audio cache: {}", path.display(), err)
+            }
+        }
+    }
+}
+
+// Files with their size and last use, access time where the filesystem keeps it
+fn collect_files(dir: &Path, files: &mut Vec<(PathBuf, u64, SystemTime)>) {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let metadata = match entry.metadata() {
+            Ok(metadata) => metadata,
+            Err(_) => continue
+        };
+        if metadata.is_dir() {
+            collect_files(&path, files);
+        } else {
+            let used = metadata.accessed()
+                .or_else(|_| metadata.modified())
+                .unwrap_or(SystemTime::UNIX_EPOCH);
+            files.push((path, metadata.len(), used));
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AudioCacheStats {
+    pub hits:   u64,
+    pub misses: u64
+}
+
+pub fn record_audio_file(is_cached: bool) {
+    if is_cached {
+        AUDIO_CACHE_HITS.fetch_add(1, Ordering::Relaxed);
+    } else {
+        AUDIO_CACHE_MISSES.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+pub fn audio_cache_stats() -> AudioCacheStats {
+    AudioCacheStats {
+        hits:   AUDIO_CACHE_HITS.load(Ordering::Relaxed),
+        misses: AUDIO_CACHE_MISSES.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use librespot::core::spotify_id::FileId;
+
+    fn audio_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("node-librespot-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("ab")).unwrap();
+        dir
+    }
+
+    fn cached(cache: &Cache, file_id: FileId) -> bool {
+        cache.file(file_id).is_some()
+    }
+
+    #[test]
+    fn zones_share_one_limit() {
+        let dir = audio_dir("zones");
+        let config = CacheConfig::new(Some(dir.join("zones")), Some(dir.join("audio")), Some(1000));
+        let zone_a = config.zone_cache("zone-a").unwrap();
+        let zone_b = config.zone_cache("zone-b").unwrap();
+        // Oldest first, each zone alone stays within the limit
+        let files = [(&zone_a, FileId([1; 20])), (&zone_b, FileId([2; 20])),
+                     (&zone_a, FileId([3; 20])), (&zone_b, FileId([4; 20]))];
+        for (cache, file_id) in files.iter() {
+            assert!(cache.save_file(*file_id, &mut &[0u8; 300][..]));
+            std::thread::sleep(Duration::from_millis(20));
+        }
+
+        config.audio_limiter.as_ref().unwrap().make_room(400);
+        assert!(!cached(&zone_a, FileId([1; 20])));
+        assert!(!cached(&zone_b, FileId([2; 20])));
+        assert!(cached(&zone_a, FileId([3; 20])));
+        assert!(cached(&zone_b, FileId([4; 20])));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn no_limiter_without_a_size_limit() {
+        assert!(CacheConfig::new(None, Some(PathBuf::from("/tmp/audio")), None).audio_limiter.is_none());
+    }
+
+    #[test]
+    fn evicts_least_recently_used_files_over_the_limit() {
+        let dir = audio_dir("evict");
+        for name in ["oldest", "older", "newest"] {
+            fs::write(dir.join("ab").join(name), vec![0u8; 400]).unwrap();
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        let config = CacheConfig::new(None, Some(dir.clone()), Some(1000));
+        config.audio_limiter.unwrap().make_room(0);
+        assert!(!dir.join("ab/oldest").exists());
+        assert!(dir.join("ab/older").exists());
+        assert!(dir.join("ab/newest").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn keeps_files_within_the_limit() {
+        let dir = audio_dir("within");
+        fs::write(dir.join("ab/file"), vec![0u8; 400]).unwrap();
+        let config = CacheConfig::new(None, Some(dir.clone()), Some(1000));
+        config.audio_limiter.unwrap().make_room(600);
+        assert!(dir.join("ab/file").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
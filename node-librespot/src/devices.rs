@@ -1,22 +1,74 @@
 use tokio::sync::mpsc::unbounded_channel;
 use tokio::sync::mpsc::{UnboundedReceiver};
+use tokio::sync::oneshot;
+use futures_util::future;
+use std::time::Duration;
 use std::collections::HashMap;
+use std::future::Future;
+use std::sync::mpsc::Sender;
 use crate::server::{ServerMessage, ServerReply};
 use crate::zone::*;
+use crate::cache::{CacheConfig};
+use crate::stream_token::{StreamSigner};
+use crate::metrics;
+use crate::formats::{parse_formats};
+
+// A zone that doesn't answer in time is left out of /zones
+const ZONE_STATUS_TIMEOUT: Duration = Duration::from_secs(2);
 
 #[derive(Debug)]
 pub enum HostMessage {
-    Stop
+    Stop,
+    Zones {
+        responder: Sender<Vec<ZoneStatus>>
+    },
+    ZoneState {
+        zone_id:   String,
+        responder: Sender<Option<ZoneStatus>>
+    }
+}
+
+// Asks each zone for its status and fills in what only devices knows. Zones
+// answer from their own tasks, so the caller spawns this instead of awaiting it.
+fn request_statuses(
+    zones:       &mut HashMap<String, Zone>,
+    last_errors: &HashMap<String, String>,
+    zone_ids:    Vec<String>
+) -> impl Future<Output = Vec<ZoneStatus>> {
+    let mut receivers = vec![];
+    for zone_id in zone_ids.iter() {
+        if let Some(zone) = zones.get_mut(zone_id) {
+            let (zone_responder, receiver) = oneshot::channel();
+            zone.send_status_request(zone_responder);
+            receivers.push(tokio::time::timeout(ZONE_STATUS_TIMEOUT, receiver));
+        }
+    }
+    let last_errors = last_errors.clone();
+    async move {
+        future::join_all(receivers).await
+            .into_iter()
+            .filter_map(|reply| match reply {
+                Ok(Ok(ServerReply::ZoneStatus(mut status))) => {
+                    status.last_error = last_errors.get(&status.zone_id).cloned();
+                    Some(status)
+                },
+                _ => None
+            })
+            .collect()
+    }
 }
 
 pub async fn run<F: Fn(SpotifyJSEvent)>(
     mut rx:         UnboundedReceiver<RoonMessage>,
     mut server_rx:  UnboundedReceiver<ServerMessage>,
     mut host_rx:    UnboundedReceiver<HostMessage>,
+    cache_config:   CacheConfig,
+    stream_signer:  StreamSigner,
     f: F
 
 ) -> std::io::Result<()> {
     let mut zones                = HashMap::<String, Zone>::new();
+    let mut last_errors          = HashMap::<String, String>::new();
     let (zones_tx, mut zones_rx) = unbounded_channel();
     loop {
         tokio::select! {
@@ -28,6 +80,19 @@ pub async fn run<F: Fn(SpotifyJSEvent)>(
                             HostMessage::Stop => {
                                 info!("GOT HOST MESSAGE");
                                 break
+                            },
+                            HostMessage::Zones { responder } => {
+                                let zone_ids = zones.keys().cloned().collect();
+                                let statuses = request_statuses(&mut zones, &last_errors, zone_ids);
+                                tokio::spawn(async move {
+                                    let _ = responder.send(statuses.await);
+                                });
+                            },
+                            HostMessage::ZoneState { zone_id, responder } => {
+                                let statuses = request_statuses(&mut zones, &last_errors, vec![zone_id]);
+                                tokio::spawn(async move {
+                                    let _ = responder.send(statuses.await.pop());
+                                });
                             }
                         }
                     },
@@ -38,6 +103,10 @@ pub async fn run<F: Fn(SpotifyJSEvent)>(
             zonemsg = zones_rx.recv() => {
                 match zonemsg {
                     Some(zonemsg) => {
+                        // Kept for status queries, the event itself goes to js as usual
+                        if let SpotifyJSEvent::ZoneError { zone_id, message, .. } = &zonemsg {
+                            last_errors.insert(zone_id.clone(), message.clone());
+                        }
                         f(zonemsg);
                     },
                     _ => break
@@ -51,11 +120,16 @@ pub async fn run<F: Fn(SpotifyJSEvent)>(
                         match msg {
                             RoonMessage::EnableZone {
                                 name,
-                                id
+                                id,
+                                bitrate,
+                                formats,
+                                preload_lead_ms
                             } => {
                                 if !zones.contains_key(&id) {
-                                    let zone = Zone::new(name.clone(), id.clone(), zones_tx.clone());
+                                    let formats = formats.as_deref().map(parse_formats).unwrap_or_default();
+                                    let zone = Zone::new(name.clone(), id.clone(), bitrate, formats, preload_lead_ms, cache_config.clone(), stream_signer.clone(), zones_tx.clone());
                                     zones.insert(id, zone);
+                                    metrics::set_active_zones(zones.len());
                                 }
                             },
                             RoonMessage::DisableZone {
@@ -65,6 +139,8 @@ pub async fn run<F: Fn(SpotifyJSEvent)>(
                                     info!("REMOVED ZONE {}",id);
                                     zone.send(cpy);
                                     zones.remove(&id);
+                                    last_errors.remove(&id);
+                                    metrics::set_active_zones(zones.len());
                                 }
                             },
                             RoonMessage::RenameZone          { id, .. } |
@@ -79,6 +155,7 @@ pub async fn run<F: Fn(SpotifyJSEvent)>(
                             RoonMessage::EndedNaturally      { id, .. } |
                             RoonMessage::OnToNext            { id, .. } |
                             RoonMessage::Volume              { id, .. } |
+                            RoonMessage::SetBitrate          { id, .. } |
                             RoonMessage::Error               { id, .. } => {
                                 if let Some(zone) = zones.get_mut(&id) {
                                     zone.send(cpy);
@@ -108,7 +185,7 @@ pub async fn run<F: Fn(SpotifyJSEvent)>(
                                 );
                             } else {
                                 info!("Bad zone requested {}", zone_id);
-                                responder.send(ServerReply::NotFound).unwrap();
+                                let _ = responder.send(ServerReply::UnknownZone);
                             }
                         },
                         ServerMessage::TrackRead {
@@ -116,7 +193,6 @@ pub async fn run<F: Fn(SpotifyJSEvent)>(
                             track_id,
                             start,
                             end,
-                            out,
                             responder
                         } => {
                             if let Some(zone) = zones.get_mut(&zone_id.clone()) {
@@ -126,14 +202,44 @@ pub async fn run<F: Fn(SpotifyJSEvent)>(
                                         track_id,
                                         start,
                                         end,
-                                        out,
                                         responder
                                     }
                                 );
                             } else {
-                                responder.send(ServerReply::NotFound).unwrap();
+                                let _ = responder.send(ServerReply::UnknownZone);
                             }
 
+                        },
+                        ServerMessage::Zones {
+                            responder
+                        } => {
+                            let zone_ids = zones.keys().cloned().collect();
+                            let statuses = request_statuses(&mut zones, &last_errors, zone_ids);
+                            tokio::spawn(async move {
+                                let _ = responder.send(ServerReply::Zones(statuses.await));
+                            });
+                        },
+                        ServerMessage::ZoneStatus {
+                            zone_id,
+                            responder
+                        } => {
+                            if zones.contains_key(&zone_id) {
+                                let statuses = request_statuses(&mut zones, &last_errors, vec![zone_id]);
+                                tokio::spawn(async move {
+                                    // Dropping the responder tells the server the zone didn't answer
+                                    if let Some(status) = statuses.await.pop() {
+                                        let _ = responder.send(ServerReply::ZoneStatus(status));
+                                    }
+                                });
+                            } else {
+                                let _ = responder.send(ServerReply::UnknownZone);
+                            }
+                        },
+                        ServerMessage::PlayerStatus {
+                            zone,
+                            responder
+                        } => {
+                            let _ = responder.send(ServerReply::ZoneStatus(zone));
                         }
                     },
                     _ => break
@@ -141,6 +247,7 @@ pub async fn run<F: Fn(SpotifyJSEvent)>(
             },
         }
     }
+    metrics::set_active_zones(0);
     info!("EXITED DEVICES THREAD");
     Ok(())
 }
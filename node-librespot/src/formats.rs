@@ -0,0 +1,89 @@
+use librespot::metadata::FileFormat;
+use librespot::playback::config::Bitrate;
+
+// Spotify prefixes its Ogg files with a header holding the normalisation data
+pub const SPOTIFY_OGG_HEADER_END: u64 = 0xa7;
+
+// Episodes are often not available as Vorbis at all
+const EPISODE_FALLBACK_FORMATS: [FileFormat; 4] = [
+    FileFormat::MP3_96,
+    FileFormat::MP3_160,
+    FileFormat::MP3_256,
+    FileFormat::MP3_320,
+];
+
+// Names as spotify's protocol spells them, e.g. "OGG_VORBIS_320" or "AAC_160"
+pub fn parse_format(name: &str) -> Option<FileFormat> {
+    match name.to_ascii_uppercase().as_str() {
+        "OGG_VORBIS_96"  => Some(FileFormat::OGG_VORBIS_96),
+        "OGG_VORBIS_160" => Some(FileFormat::OGG_VORBIS_160),
+        "OGG_VORBIS_320" => Some(FileFormat::OGG_VORBIS_320),
+        "MP3_96"         => Some(FileFormat::MP3_96),
+        "MP3_160"        => Some(FileFormat::MP3_160),
+        "MP3_160_ENC"    => Some(FileFormat::MP3_160_ENC),
+        "MP3_256"        => Some(FileFormat::MP3_256),
+        "MP3_320"        => Some(FileFormat::MP3_320),
+        "MP4_128"        => Some(FileFormat::MP4_128),
+        "MP4_128_DUAL"   => Some(FileFormat::MP4_128_DUAL),
+        "AAC_160"        => Some(FileFormat::AAC_160),
+        "AAC_320"        => Some(FileFormat::AAC_320),
+        "OTHER3"         => Some(FileFormat::OTHER3),
+        "OTHER5"         => Some(FileFormat::OTHER5),
+        _ => {
+            // Lossless formats aren't part of this librespot's FileFormat yet
+            warn!("Unsupported audio format {}", name);
+            None
+        }
+    }
+}
+
+pub fn parse_formats(names: &[String]) -> Vec<FileFormat> {
+    let mut formats = vec![];
+    for format in names.iter().filter_map(|name| parse_format(name)) {
+        if !formats.contains(&format) {
+            formats.push(format);
+        }
+    }
+    formats
+}
+
+// Used when a zone didn't configure any formats, Vorbis around the bitrate first
+pub fn default_formats(bitrate: Bitrate, is_episode: bool) -> Vec<FileFormat> {
+    let mut formats = match bitrate {
+        Bitrate::Bitrate96 => vec![
+            FileFormat::OGG_VORBIS_96,
+            FileFormat::OGG_VORBIS_160,
+            FileFormat::OGG_VORBIS_320,
+        ],
+        Bitrate::Bitrate160 => vec![
+            FileFormat::OGG_VORBIS_160,
+            FileFormat::OGG_VORBIS_96,
+            FileFormat::OGG_VORBIS_320,
+        ],
+        Bitrate::Bitrate320 => vec![
+            FileFormat::OGG_VORBIS_320,
+            FileFormat::OGG_VORBIS_160,
+            FileFormat::OGG_VORBIS_96,
+        ],
+    };
+    if is_episode {
+        formats.extend_from_slice(&EPISODE_FALLBACK_FORMATS);
+    }
+    formats
+}
+
+pub fn is_ogg_vorbis(format: FileFormat) -> bool {
+    matches!(format,
+        FileFormat::OGG_VORBIS_96 |
+        FileFormat::OGG_VORBIS_160 |
+        FileFormat::OGG_VORBIS_320)
+}
+
+// Bytes to skip before the audio container starts
+pub fn header_offset(format: FileFormat) -> u64 {
+    if is_ogg_vorbis(format) {
+        SPOTIFY_OGG_HEADER_END
+    } else {
+        0
+    }
+}
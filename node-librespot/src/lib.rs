@@ -6,39 +6,84 @@ use tokio::sync::mpsc::{unbounded_channel};
 use tokio::sync::mpsc::{UnboundedSender};
 use std::thread;
 use actix_web::{rt};
+use serde::Serialize;
 use serde::de::{DeserializeOwned};
 use serde_json;
 use neon::object::This;
 use std::thread::JoinHandle;
-use std::sync::mpsc::channel;
+use std::sync::mpsc::{channel, Sender};
 use actix_web::dev::ServerHandle;
 #[macro_use] extern crate log;
 extern crate simplelog;
 use simplelog::*;
 use std::fs::File;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::env;
 use std::process::exit;
+use std::time::Duration;
 
 mod playerinternal;
 mod zone;
 mod player;
 mod server;
 mod devices;
+mod cache;
+mod transcode;
+mod stream_token;
+mod metrics;
+mod formats;
+mod allow_list;
 
-use zone::{SpotifyJSEvent, RoonMessage};
+use zone::{SpotifyJSEvent, RoonMessage, ZoneStatus};
 use devices::{HostMessage};
+use cache::{CacheConfig};
+use stream_token::{StreamSigner};
+use allow_list::{ClientAllowList};
 
 type BoxedHost = JsBox<RefCell<Host>>;
 
+const DEFAULT_STREAM_LOAD_TIMEOUT: Duration = Duration::from_secs(15);
+// Devices gives each zone 2s to answer, the promise is rejected if it never does
+const HOST_QUERY_TIMEOUT: Duration = Duration::from_secs(3);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum HostState {
+    Stopped,
+    Starting,
+    Running,
+    Stopping
+}
+
+// Everything start() brings up, handed back to the js thread once it's running
+struct RunningHost {
+    server_url:           String,
+    server_port:          u16,
+    server_handle:        ServerHandle,
+    server_thread_handle: JoinHandle<()>,
+    devices_tx:           UnboundedSender<RoonMessage>,
+    devices_handle:       JoinHandle<()>,
+    host_devices_tx:      UnboundedSender<HostMessage>,
+}
+
+// Copy of the configuration for the thread doing the start
+struct StartConfig {
+    base_url:            Option<String>,
+    listen_port:         Option<u16>,
+    cache_config:        CacheConfig,
+    stream_load_timeout: Duration,
+    status_clients:      ClientAllowList,
+}
+
 pub struct Host {
-    server_url:           Option<String>,
+    base_url:             Option<String>, // What was asked for in init
+    listen_port:          Option<u16>,
+    server_url:           Option<String>, // What the server is bound to while running
     server_port:          Option<u16>,
-    server_handle:        Option<ServerHandle>,
-    server_thread_handle: Option<JoinHandle<()>>,
-    devices_tx:           Option<UnboundedSender<RoonMessage>>,
-    devices_handle:       Option<JoinHandle<()>>,
-    host_devices_tx:      Option<UnboundedSender<HostMessage>>,
+    state:                HostState,
+    running:              Option<RunningHost>,
+    cache_config:         CacheConfig,
+    stream_load_timeout:  Duration,
+    status_clients:       ClientAllowList, // Who may read /zones and /metrics
     js_callback:          Root<JsFunction>
 }
 
@@ -50,47 +95,78 @@ impl Finalize for Host {
 }
 
 impl Host {
-    fn new(base_url: Option<String>, listen_port: Option<u16>, callback: Root<JsFunction>) -> Self
+    fn new(base_url: Option<String>, listen_port: Option<u16>, cache_config: CacheConfig, stream_load_timeout: Duration, status_clients: ClientAllowList, callback: Root<JsFunction>) -> Self
     {
         Host {
-            server_url:           base_url,
-            server_port:          listen_port,
-            server_handle:        None,
-            server_thread_handle: None,
-            devices_tx:           None,
-            devices_handle:       None,
-            host_devices_tx:      None,
+            base_url,
+            listen_port,
+            server_url:           None,
+            server_port:          None,
+            state:                HostState::Stopped,
+            running:              None,
+            cache_config,
+            stream_load_timeout,
+            status_clients,
             js_callback:          callback
         }
     }
 
-    fn start(&mut self, this: Root<JsObject>, js_callback: Root<JsFunction>, jschannel: neon::event::Channel) {
+    fn start_config(&self) -> StartConfig {
+        StartConfig {
+            base_url:            self.base_url.clone(),
+            listen_port:         self.listen_port,
+            cache_config:        self.cache_config.clone(),
+            stream_load_timeout: self.stream_load_timeout,
+            status_clients:      self.status_clients.clone(),
+        }
+    }
+
+    // Blocks until the server is bound, so it runs off the js thread
+    fn start(config: StartConfig, this: Root<JsObject>, js_callback: Root<JsFunction>, jschannel: neon::event::Channel) -> Result<RunningHost, String> {
         // Query track info from http server for each zone
         let (server_tx, server_rx) = channel();
         let (devices_server_tx, devices_server_rx) = unbounded_channel();
+        // Fresh key per start, urls from a previous run stop working
+        let stream_signer = StreamSigner::new();
 
 
         // HTTP Server
-        let port = self.server_port.clone();
-        let url  = self.server_url.clone();
+        let port = config.listen_port;
+        let url  = config.base_url;
+        let load_timeout = config.stream_load_timeout;
+        let server_signer = stream_signer.clone();
+        let status_clients = config.status_clients;
         let server_thread_handle = thread::spawn(move || {
             let server_future = server::run_server(
                     devices_server_tx,        // Send to devices thread
                     server_tx,                // Call back to server_rx from player thread
                     url,
-                    port
+                    port,
+                    load_timeout,
+                    server_signer,
+                    status_clients
                 );
-            rt::System::new().block_on(server_future).unwrap();
+            // Bind errors were already reported through server_tx
+            if let Err(e) = rt::System::new().block_on(server_future) {
+                error!("Server exited with error {}", e);
+            }
             info!("EXITED SERVER THREAD");
         });
-        self.server_thread_handle = Some(server_thread_handle);
-        let (server_handle, server_url, port) = server_rx.recv().unwrap();
-        self.server_handle = Some(server_handle);
-        self.server_url    = Some(server_url);
-        self.server_port   = Some(port);
+        let (server_handle, server_url, server_port) = match server_rx.recv() {
+            Ok(Ok(bound)) => bound,
+            Ok(Err(e))    => {
+                let _ = server_thread_handle.join();
+                return Err(e);
+            },
+            Err(_) => {
+                let _ = server_thread_handle.join();
+                return Err("Server thread exited before it started listening".to_string());
+            }
+        };
 
         let (host_devices_tx, devices_host_rx) = unbounded_channel();
         let (devices_tx, devices_rx)           = unbounded_channel();
+        let cache_config                       = config.cache_config;
 
         // Spotify 
         let devices_handle = thread::spawn(move || {
@@ -100,6 +176,8 @@ impl Host {
                 devices_rx,        // receive from roon
                 devices_server_rx, // receive from http server
                 devices_host_rx,   // receive shutdown command from host
+                cache_config,      // credentials, volume and audio files
+                stream_signer,     // signs the stream urls sent with Play/Preload
                                    //
                                    // Call back into javascript event loop when spotify tells a
                                    // zone to do something
@@ -124,42 +202,72 @@ impl Host {
             rt.block_on(devices_future).unwrap();
         });
 
-        self.devices_tx      = Some(devices_tx);
-        self.host_devices_tx = Some(host_devices_tx);
-        self.devices_handle  = Some(devices_handle);
+        Ok(RunningHost {
+            server_url,
+            server_port,
+            server_handle,
+            server_thread_handle,
+            devices_tx,
+            devices_handle,
+            host_devices_tx,
+        })
     }
 
-    fn send_roon_message(&mut self, msg: RoonMessage) {
-        if self.devices_tx.is_some() {
-            self.devices_tx.as_ref().unwrap()
-            .send(msg).unwrap();
-        }
+    fn send_roon_message(&mut self, msg: RoonMessage) -> Result<(), String> {
+        let running = self.running.as_ref()
+            .ok_or_else(|| "Host is not running".to_string())?;
+        running.devices_tx.send(msg)
+            .map_err(|_| "Devices thread is not running".to_string())
     }
 
-    fn stop(&mut self) {
-        if self.host_devices_tx.is_some() {
-            self.host_devices_tx.as_ref().unwrap()
-                .send(HostMessage::Stop).unwrap();
-
-            // Block until shutdown stops
-            if let Some(handle) = self.devices_handle.take() {
-                handle.join().unwrap();
-                self.devices_handle  = None;
-                self.host_devices_tx = None;
-                self.devices_tx      = None;
+    // Resolves with the answer as JSON, or with `stopped` when the host hasn't been
+    // started. The answer is waited for off the js thread.
+    fn query<'a, T>(&self, cx: &mut FunctionContext<'a>, stopped: T, msg: impl FnOnce(Sender<T>) -> HostMessage) -> JsResult<'a, JsPromise>
+        where T: Serialize + Send + 'static
+    {
+        let (deferred, promise) = cx.promise();
+        let host_devices_tx = match self.running.as_ref() {
+            Some(running) => &running.host_devices_tx,
+            None => {
+                let stopped = cx.string(serde_json::to_string(&stopped).unwrap());
+                deferred.resolve(cx, stopped);
+                return Ok(promise);
             }
-
-            // Block until server shuts down
-            if let Some(handle) = self.server_handle.take() {
-                futures_executor::block_on(handle.stop(true));
-                self.server_handle = None;
-                if let Some(thread_handle) = self.server_thread_handle.take() {
-                    thread_handle.join().unwrap();
-                    self.server_thread_handle = None;
+        };
+        let (responder, receiver) = channel();
+        if host_devices_tx.send(msg(responder)).is_err() {
+            let err = cx.error("Devices thread is not running")?;
+            deferred.reject(cx, err);
+            return Ok(promise);
+        }
+        let settle_channel = cx.channel();
+        thread::spawn(move || {
+            let answer = receiver.recv_timeout(HOST_QUERY_TIMEOUT);
+            deferred.settle_with(&settle_channel, move |mut cx| {
+                match answer {
+                    Ok(answer) => Ok(cx.string(serde_json::to_string(&answer).unwrap())),
+                    Err(_)     => cx.throw_error("Timed out waiting for zone state")
                 }
-            }
+            });
+        });
+        Ok(promise)
+    }
+
+    // Blocks until both threads exit, so it runs off the js thread
+    fn stop(running: RunningHost) {
+        let _ = running.host_devices_tx.send(HostMessage::Stop);
+        if running.devices_handle.join().is_err() {
+            error!("Devices thread panicked");
+        }
+        futures_executor::block_on(running.server_handle.stop(true));
+        if running.server_thread_handle.join().is_err() {
+            error!("Server thread panicked");
         }
     }
+
+    fn busy_error(&self) -> String {
+        format!("Host is {:?}, wait for it to finish", self.state)
+    }
 }
 impl Drop for Host {
     fn drop(&mut self) {
@@ -167,16 +275,87 @@ impl Drop for Host {
     }
 }
 
-// This will throw if deserialization fails
-fn parse<C,T>(cx: &mut CallContext<C>) -> T
+fn address<'a, C: Context<'a>>(cx: &mut C, url: String, port: u16) -> JsResult<'a, JsObject> {
+    let obj  = cx.empty_object();
+    let url  = cx.string(url);
+    let port = cx.number(port);
+    obj.set(cx, "url",  url)?;
+    obj.set(cx, "port", port)?;
+    Ok(obj)
+}
+
+// Takes a JSON string or a plain object. The inner Err describes what is wrong
+// with the message, including the path to the failing field.
+fn parse<C,T>(cx: &mut CallContext<C>) -> NeonResult<Result<T, String>>
 where
     C: This,
     T: DeserializeOwned
 {
-    let x    = cx.argument::<JsString>(0).unwrap();
-    let s    = x.value(cx).clone();
-    let t: T = serde_json::from_str(&s).unwrap();
-    return t;
+    let arg = match cx.argument_opt(0) {
+        Some(arg) => arg,
+        None => return Ok(Err("Missing message argument".to_string()))
+    };
+    let parsed = if let Ok(s) = arg.downcast::<JsString,_>(cx) {
+        let s = s.value(cx);
+        serde_path_to_error::deserialize(&mut serde_json::Deserializer::from_str(&s))
+    } else {
+        let value = js_to_json(cx, arg, 0)?;
+        serde_path_to_error::deserialize(value)
+    };
+    Ok(parsed.map_err(|e| format!("Invalid message at '{}': {}", e.path(), e.inner())))
+}
+
+// Largest integer a JS number holds exactly
+const MAX_SAFE_INTEGER: f64 = 9007199254740991.0;
+// Roon messages nest a few levels at most, deeper means a cyclic or runaway object
+const MAX_MESSAGE_DEPTH: usize = 32;
+
+fn js_to_json<'a, C: Context<'a>>(cx: &mut C, value: Handle<'a, JsValue>, depth: usize) -> NeonResult<serde_json::Value> {
+    use serde_json::Value;
+    if depth > MAX_MESSAGE_DEPTH {
+        return cx.throw_error(format!("Message is nested deeper than {} levels, is it cyclic?", MAX_MESSAGE_DEPTH));
+    }
+    if value.is_a::<JsNull, _>(cx) || value.is_a::<JsUndefined, _>(cx) {
+        return Ok(Value::Null);
+    }
+    if let Ok(b) = value.downcast::<JsBoolean, _>(cx) {
+        return Ok(Value::Bool(b.value(cx)));
+    }
+    if let Ok(n) = value.downcast::<JsNumber, _>(cx) {
+        // Whole numbers become integers so they deserialize into u16/u32 fields
+        let n = n.value(cx);
+        return Ok(if n.fract() == 0.0 && n.abs() <= MAX_SAFE_INTEGER {
+            Value::from(n as i64)
+        } else {
+            Value::from(n)
+        });
+    }
+    if let Ok(s) = value.downcast::<JsString, _>(cx) {
+        return Ok(Value::String(s.value(cx)));
+    }
+    if let Ok(array) = value.downcast::<JsArray, _>(cx) {
+        let items = array.to_vec(cx)?;
+        let mut values = Vec::with_capacity(items.len());
+        for item in items {
+            values.push(js_to_json(cx, item, depth + 1)?);
+        }
+        return Ok(Value::Array(values));
+    }
+    if let Ok(obj) = value.downcast::<JsObject, _>(cx) {
+        let keys = obj.get_own_property_names(cx)?.to_vec(cx)?;
+        let mut map = serde_json::Map::new();
+        for key in keys {
+            let key = key.to_string(cx)?.value(cx);
+            let item: Handle<JsValue> = obj.get(cx, key.as_str())?;
+            // Functions and the like have no JSON form, skip them like JSON.stringify
+            if item.is_a::<JsFunction, _>(cx) {
+                continue;
+            }
+            map.insert(key, js_to_json(cx, item, depth + 1)?);
+        }
+        return Ok(Value::Object(map));
+    }
+    Ok(Value::Null)
 }
 
 impl Host {
@@ -205,31 +384,175 @@ impl Host {
         };
 
         let callback_function = cx.argument::<JsFunction>(2)?.root(&mut cx);
+
+        let cache_location = cx.argument_opt(3);
+        let cache_dir = match cache_location {
+            Some(p) => {
+                match p.downcast::<JsString,_>(&mut cx) {
+                    Ok(n) => Some(PathBuf::from(n.value(&mut cx))),
+                    _ => None
+                }
+            },
+            _ => None
+        };
+
+        let audio_cache_location = cx.argument_opt(4);
+        let audio_cache_dir = match audio_cache_location {
+            Some(p) => {
+                match p.downcast::<JsString,_>(&mut cx) {
+                    Ok(n) => Some(PathBuf::from(n.value(&mut cx))),
+                    _ => None
+                }
+            },
+            _ => None
+        };
+
+        let audio_cache_size = cx.argument_opt(5);
+        let audio_size_limit = match audio_cache_size {
+            Some(p) => {
+                match p.downcast::<JsNumber,_>(&mut cx) {
+                    Ok(n) => Some(n.value(&mut cx) as u64),
+                    _ => None
+                }
+            },
+            _ => None
+        };
+
+        let stream_load_timeout_ms = cx.argument_opt(6);
+        let stream_load_timeout = match stream_load_timeout_ms {
+            Some(p) => {
+                match p.downcast::<JsNumber,_>(&mut cx) {
+                    Ok(n) => Duration::from_millis(n.value(&mut cx) as u64),
+                    _ => DEFAULT_STREAM_LOAD_TIMEOUT
+                }
+            },
+            _ => DEFAULT_STREAM_LOAD_TIMEOUT
+        };
+
+        // Addresses or networks allowed to read status and metrics, local networks by default
+        let status_clients = match cx.argument_opt(7) {
+            Some(p) => {
+                match p.downcast::<JsArray,_>(&mut cx) {
+                    Ok(entries) => {
+                        let mut clients = vec![];
+                        for entry in entries.to_vec(&mut cx)? {
+                            let entry = entry.downcast_or_throw::<JsString,_>(&mut cx)?;
+                            clients.push(entry.value(&mut cx));
+                        }
+                        match ClientAllowList::parse(&clients) {
+                            Ok(allow_list) => allow_list,
+                            Err(e) => return cx.throw_error(e)
+                        }
+                    },
+                    _ => ClientAllowList::default()
+                }
+            },
+            _ => ClientAllowList::default()
+        };
+
+        let cache_config = CacheConfig::new(cache_dir, audio_cache_dir, audio_size_limit);
+
         let host = RefCell::new(Host::new(
                 url,
                 port,
+                cache_config,
+                stream_load_timeout,
+                status_clients,
                 callback_function
         ));
         Ok(cx.boxed(host))
     }
 
+    // Resolves with { url, port } once the server listens. Starting a running host
+    // resolves with the current address.
     fn js_start(mut cx: FunctionContext) -> JsResult<JsPromise> {
         let host = cx.this().downcast_or_throw::<BoxedHost, _>(&mut cx)?;
         let mut host = host.borrow_mut();
-        let channel = cx.channel();
-        let callback = host.js_callback.clone(&mut cx);
-        host.start(cx.this().root(&mut cx), callback, channel);
         let (deferred, promise) = cx.promise();
-        deferred.settle_with(&cx.channel(), move |mut cx| Ok(cx.number(42)));
+        match host.state {
+            HostState::Running => {
+                let address = address(&mut cx, host.server_url.clone().unwrap_or_default(), host.server_port.unwrap_or(0))?;
+                deferred.resolve(&mut cx, address);
+                return Ok(promise);
+            },
+            HostState::Starting | HostState::Stopping => {
+                let err = cx.error(host.busy_error())?;
+                deferred.reject(&mut cx, err);
+                return Ok(promise);
+            },
+            HostState::Stopped => ()
+        }
+        host.state = HostState::Starting;
+
+        let config          = host.start_config();
+        let this            = cx.this().root(&mut cx);
+        let this_for_settle = cx.this().root(&mut cx);
+        let callback        = host.js_callback.clone(&mut cx);
+        let jschannel       = cx.channel();
+        let settle_channel  = cx.channel();
+        thread::spawn(move || {
+            let result = Host::start(config, this, callback, jschannel);
+            deferred.settle_with(&settle_channel, move |mut cx| {
+                let this = this_for_settle.into_inner(&mut cx);
+                let host = this.downcast_or_throw::<BoxedHost, _>(&mut cx)?;
+                let mut host = host.borrow_mut();
+                match result {
+                    Ok(running) => {
+                        info!("Host listening on {}:{}", running.server_url, running.server_port);
+                        host.server_url  = Some(running.server_url.clone());
+                        host.server_port = Some(running.server_port);
+                        host.running     = Some(running);
+                        host.state       = HostState::Running;
+                        address(&mut cx, host.server_url.clone().unwrap_or_default(), host.server_port.unwrap_or(0))
+                    },
+                    Err(e) => {
+                        error!("Host failed to start: {}", e);
+                        host.state = HostState::Stopped;
+                        cx.throw_error(e)
+                    }
+                }
+            });
+        });
         Ok(promise)
     }
 
+    // Stopping a stopped host resolves right away
     fn js_stop(mut cx: FunctionContext) -> JsResult<JsPromise> {
         let host = cx.this().downcast_or_throw::<BoxedHost, _>(&mut cx)?;
         let mut host = host.borrow_mut();
-        host.stop();
         let (deferred, promise) = cx.promise();
-        deferred.settle_with(&cx.channel(), move |mut cx| Ok(cx.number(42)));
+        match host.state {
+            HostState::Stopped => {
+                let undefined = cx.undefined();
+                deferred.resolve(&mut cx, undefined);
+                return Ok(promise);
+            },
+            HostState::Starting | HostState::Stopping => {
+                let err = cx.error(host.busy_error())?;
+                deferred.reject(&mut cx, err);
+                return Ok(promise);
+            },
+            HostState::Running => ()
+        }
+        host.state = HostState::Stopping;
+
+        let running         = host.running.take();
+        let this_for_settle = cx.this().root(&mut cx);
+        let settle_channel  = cx.channel();
+        thread::spawn(move || {
+            if let Some(running) = running {
+                Host::stop(running);
+            }
+            deferred.settle_with(&settle_channel, move |mut cx| {
+                let this = this_for_settle.into_inner(&mut cx);
+                let host = this.downcast_or_throw::<BoxedHost, _>(&mut cx)?;
+                let mut host = host.borrow_mut();
+                host.server_url  = None;
+                host.server_port = None;
+                host.state       = HostState::Stopped;
+                Ok(cx.undefined())
+            });
+        });
         Ok(promise)
     }
 
@@ -253,13 +576,48 @@ impl Host {
         }
     }
 
+    fn js_audio_cache_stats(mut cx: FunctionContext) -> JsResult<JsObject> {
+        let stats  = cache::audio_cache_stats();
+        let obj    = cx.empty_object();
+        let hits   = cx.number(stats.hits as f64);
+        let misses = cx.number(stats.misses as f64);
+        obj.set(&mut cx, "hits",   hits)?;
+        obj.set(&mut cx, "misses", misses)?;
+        Ok(obj)
+    }
+
+    // Both resolve with JSON, the js wrapper parses it
+    fn js_zones(mut cx: FunctionContext) -> JsResult<JsPromise> {
+        let host = cx.this().downcast_or_throw::<BoxedHost, _>(&mut cx)?;
+        let host = host.borrow();
+        host.query(&mut cx, Vec::<ZoneStatus>::new(), |responder| HostMessage::Zones { responder })
+    }
+
+    fn js_zone_state(mut cx: FunctionContext) -> JsResult<JsPromise> {
+        let host    = cx.this().downcast_or_throw::<BoxedHost, _>(&mut cx)?;
+        let zone_id = cx.argument::<JsString>(0)?.value(&mut cx);
+        let host    = host.borrow();
+        host.query(&mut cx, None, |responder| HostMessage::ZoneState { zone_id, responder })
+    }
+
     fn js_send_roon_message(mut cx: FunctionContext) -> JsResult<JsPromise> {
         let host     = cx.this().downcast_or_throw::<BoxedHost, _>(&mut cx)?;
         let mut host = host.borrow_mut();
-        let msg: RoonMessage = parse(&mut cx);
-        host.send_roon_message(msg);
         let (deferred, promise) = cx.promise();
-        deferred.settle_with(&cx.channel(), move |mut cx| Ok(cx.number(42)));
+        let sent = match parse::<_, RoonMessage>(&mut cx)? {
+            Ok(msg) => host.send_roon_message(msg),
+            Err(e)  => Err(e)
+        };
+        match sent {
+            Ok(()) => {
+                deferred.settle_with(&cx.channel(), move |mut cx| Ok(cx.number(42)));
+            },
+            Err(e) => {
+                error!("Rejected roon message: {}", e);
+                let err = cx.error(e)?;
+                deferred.reject(&mut cx, err);
+            }
+        }
         Ok(promise)
     }
 
@@ -303,5 +661,8 @@ impl Host {
     cx.export_function("send_roon_message",  Host::js_send_roon_message)?;
     cx.export_function("port",               Host::js_port)?;
     cx.export_function("url",                Host::js_url)?;
+    cx.export_function("audio_cache_stats",  Host::js_audio_cache_stats)?;
+    cx.export_function("zones",              Host::js_zones)?;
+    cx.export_function("zone_state",         Host::js_zone_state)?;
     Ok(())
  }
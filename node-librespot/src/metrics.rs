@@ -0,0 +1,158 @@
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::cache;
+
+// Upper bounds in seconds, the +Inf bucket is the total count
+const READ_BUCKETS: [f64; 8] = [0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0];
+const LOAD_BUCKETS: [f64; 8] = [0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0];
+
+pub struct Histogram {
+    bounds:       &'static [f64; 8],
+    buckets:      [AtomicU64; 8],
+    count:        AtomicU64,
+    sum_micros:   AtomicU64,
+}
+
+impl Histogram {
+    const fn new(bounds: &'static [f64; 8]) -> Histogram {
+        const ZERO: AtomicU64 = AtomicU64::new(0);
+        Histogram {
+            bounds,
+            buckets:    [ZERO; 8],
+            count:      AtomicU64::new(0),
+            sum_micros: AtomicU64::new(0),
+        }
+    }
+
+    fn observe(&self, elapsed: Duration) {
+        let secs = elapsed.as_secs_f64();
+        for (bound, bucket) in self.bounds.iter().zip(self.buckets.iter()) {
+            if secs <= *bound {
+                bucket.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros.fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    fn write(&self, out: &mut String, name: &str, help: &str) {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+        for (bound, bucket) in self.bounds.iter().zip(self.buckets.iter()) {
+            let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, bucket.load(Ordering::Relaxed));
+        }
+        let count = self.count.load(Ordering::Relaxed);
+        let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, count);
+        let _ = writeln!(out, "{}_sum {}", name, self.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0);
+        let _ = writeln!(out, "{}_count {}", name, count);
+    }
+}
+
+static TRACK_READ_SECONDS: Histogram = Histogram::new(&READ_BUCKETS);
+static TRACK_LOAD_SECONDS: Histogram = Histogram::new(&LOAD_BUCKETS);
+static TRACK_LOAD_FAILURES: AtomicU64 = AtomicU64::new(0);
+static PRELOAD_HITS:        AtomicU64 = AtomicU64::new(0);
+static PRELOAD_MISSES:      AtomicU64 = AtomicU64::new(0);
+static RECONNECT_ATTEMPTS:  AtomicU64 = AtomicU64::new(0);
+static ACTIVE_ZONES:        AtomicU64 = AtomicU64::new(0);
+
+// Few zones and few error kinds, a list is simpler than a map in a static
+static ZONE_BYTES:      Mutex<Vec<(String, u64)>>       = Mutex::new(Vec::new());
+static STREAM_ERRORS:   Mutex<Vec<(&'static str, u64)>> = Mutex::new(Vec::new());
+
+fn add_labelled<K: PartialEq + Clone>(values: &Mutex<Vec<(K, u64)>>, label: &K, n: u64) {
+    let mut values = values.lock().unwrap();
+    match values.iter_mut().find(|(l, _)| l == label) {
+        Some((_, value)) => *value += n,
+        None => values.push((label.clone(), n))
+    }
+}
+
+pub fn record_bytes_served(zone_id: &str, bytes: usize) {
+    add_labelled(&ZONE_BYTES, &zone_id.to_string(), bytes as u64);
+}
+
+pub fn record_stream_error(kind: &'static str) {
+    add_labelled(&STREAM_ERRORS, &kind, 1);
+}
+
+pub fn observe_track_read(elapsed: Duration) {
+    TRACK_READ_SECONDS.observe(elapsed);
+}
+
+pub fn observe_track_load(elapsed: Duration, loaded: bool) {
+    if loaded {
+        TRACK_LOAD_SECONDS.observe(elapsed);
+    } else {
+        TRACK_LOAD_FAILURES.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+pub fn record_preload(hit: bool) {
+    if hit {
+        PRELOAD_HITS.fetch_add(1, Ordering::Relaxed);
+    } else {
+        PRELOAD_MISSES.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+pub fn record_reconnect_attempt() {
+    RECONNECT_ATTEMPTS.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn set_active_zones(count: usize) {
+    ACTIVE_ZONES.store(count as u64, Ordering::Relaxed);
+}
+
+fn write_counter(out: &mut String, name: &str, help: &str, value: u64) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} counter", name);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+// Prometheus text exposition format
+pub fn render() -> String {
+    let mut out = String::new();
+
+    let _ = writeln!(out, "# HELP spotify_stream_bytes_total Audio bytes served over http");
+    let _ = writeln!(out, "# TYPE spotify_stream_bytes_total counter");
+    for (zone_id, bytes) in ZONE_BYTES.lock().unwrap().iter() {
+        let _ = writeln!(out, "spotify_stream_bytes_total{{zone=\"{}\"}} {}", escape_label(zone_id), bytes);
+    }
+
+    let _ = writeln!(out, "# HELP spotify_stream_errors_total Failed stream requests by reason");
+    let _ = writeln!(out, "# TYPE spotify_stream_errors_total counter");
+    for (kind, count) in STREAM_ERRORS.lock().unwrap().iter() {
+        let _ = writeln!(out, "spotify_stream_errors_total{{reason=\"{}\"}} {}", kind, count);
+    }
+
+    TRACK_READ_SECONDS.write(&mut out, "spotify_track_read_seconds", "Time for a player to answer a TrackRead");
+    TRACK_LOAD_SECONDS.write(&mut out, "spotify_track_load_seconds", "Time to fetch and open a track");
+    write_counter(&mut out, "spotify_track_load_failures_total", "Tracks that failed to load",
+        TRACK_LOAD_FAILURES.load(Ordering::Relaxed));
+
+    let _ = writeln!(out, "# HELP spotify_preload_total Loads served from the preloaded track or not");
+    let _ = writeln!(out, "# TYPE spotify_preload_total counter");
+    let _ = writeln!(out, "spotify_preload_total{{result=\"hit\"}} {}", PRELOAD_HITS.load(Ordering::Relaxed));
+    let _ = writeln!(out, "spotify_preload_total{{result=\"miss\"}} {}", PRELOAD_MISSES.load(Ordering::Relaxed));
+
+    write_counter(&mut out, "spotify_reconnect_attempts_total", "Session reconnects and zone restarts",
+        RECONNECT_ATTEMPTS.load(Ordering::Relaxed));
+
+    let stats = cache::audio_cache_stats();
+    write_counter(&mut out, "spotify_audio_cache_hits_total", "Audio files opened from the cache", stats.hits);
+    write_counter(&mut out, "spotify_audio_cache_misses_total", "Audio files fetched from Spotify", stats.misses);
+
+    let _ = writeln!(out, "# HELP spotify_active_zones Zones currently enabled");
+    let _ = writeln!(out, "# TYPE spotify_active_zones gauge");
+    let _ = writeln!(out, "spotify_active_zones {}", ACTIVE_ZONES.load(Ordering::Relaxed));
+
+    out
+}
+
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
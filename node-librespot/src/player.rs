@@ -2,6 +2,7 @@
 use std::future::Future;
 use std::pin::Pin;
 use std::{thread};
+use std::collections::VecDeque;
 
 use tokio::sync::{mpsc};
 use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
@@ -9,6 +10,8 @@ use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
 use crate::playerinternal::*;
 use crate::server::{ServerMessage};
 use crate::zone::{SpotifyJSEvent,RoonMessage};
+use crate::stream_token::{StreamSigner};
+use crate::cache::{AudioCacheLimiter};
 
 use librespot::playback::player::{PlayerEventChannel, PlayerEvent};
 use librespot::connect::spirc::{PlayerImpl};
@@ -16,6 +19,7 @@ use librespot::playback::config::{PlayerConfig};
 use librespot::core::session::Session;
 use librespot::core::spotify_id::SpotifyId;
 use librespot::core::util::SeqGenerator;
+use librespot::metadata::FileFormat;
 use std::sync::{Mutex,Arc};
 
 pub struct Player {
@@ -51,6 +55,10 @@ impl Player {
         player_server_rx: Arc<Mutex<UnboundedReceiver<ServerMessage>>>,
         player_roon_rx:   Arc<Mutex<UnboundedReceiver<RoonMessage>>>,
         js_tx: Arc<Mutex<UnboundedSender<SpotifyJSEvent>>>,
+        stream_signer: StreamSigner,
+        formats: Vec<FileFormat>,
+        audio_cache_limiter: Option<Arc<AudioCacheLimiter>>,
+        preload_timer: Arc<Mutex<PreloadTimer>>,
         zone_id: String
     ) -> (Player, PlayerEventChannel)
     {
@@ -70,8 +78,17 @@ impl Player {
                 player_server_rx,
                 player_roon_rx,
                 js_tx,
+                stream_signer,
+                formats,
+                audio_cache_limiter,
                 zone_id,
-                yet_to_play: true
+                yet_to_play: true,
+                parked_requests: vec![],
+                recent_tracks: VecDeque::new(),
+                volume: None,
+                preload_timer,
+                playback_errors: 0,
+                retried_track: None
             };
 
             // While PlayerInternal is written as a future, it still contains blocking code.
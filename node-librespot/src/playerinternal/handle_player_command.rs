@@ -17,9 +17,9 @@ impl PlayerInternal {
             PlayerCommand::Seek(position_ms)          => self.handle_seek(position_ms),
             PlayerCommand::AddEventSender(sender)     => self.event_senders.push(sender),
             PlayerCommand::EmitVolumeSetEvent(volume) => self.handle_volume_set(volume),
-            // XXX No idea
-            PlayerCommand::SetAutoNormaliseAsAlbum(_setting) => {
-                //self.auto_normalise_as_album = setting
+            // Picks album or track gain for the next Play/Preload sent to roon
+            PlayerCommand::SetAutoNormaliseAsAlbum(setting) => {
+                self.auto_normalise_as_album = setting
             }
         }
     }
@@ -39,15 +39,16 @@ impl PlayerInternal {
             let old_track = match mem::replace(&mut self.state, PlayerState::Invalid) {
                 PlayerState::Paused { track, .. } => track,
                 _ => {
-                    error!("Not in paused state!");
-                    exit(1);
+                    return self.invalid_state("Not in paused state!");
                 }
             };
             if self.yet_to_play {
                 // Case 2: We loaded a track but haven't played yet
                 self.send_to_roon(SpotifyJSEvent::Play {
                     zone_id:          self.zone_id.clone(),
-                    now_playing_info: RoonNowPlaying::new(old_track.audio.clone()),
+                    now_playing_info: old_track.now_playing.clone(),
+                    replay_gain: self.replay_gain(&old_track),
+                    stream_url:  self.stream_url(&old_track),
                     preload_id: preload_id.clone(),
                     position_ms,
                     play_request_id,
@@ -89,8 +90,7 @@ impl PlayerInternal {
             let loader = match mem::replace(&mut self.state, PlayerState::Invalid) {
                 PlayerState::Loading { loader, .. } => loader,
                 _ => {
-                    error!("Not in loading state!");
-                    exit(1);
+                    return self.invalid_state("Not in loading state!");
                 }
             };
            self.state = PlayerState::Loading {
@@ -121,8 +121,7 @@ impl PlayerInternal {
             let old_track = match mem::replace(&mut self.state, PlayerState::Invalid) {
                 PlayerState::Playing { track, .. } => track,
                 _ => {
-                    info!("Something is really wrong!");
-                    exit(1);
+                    return self.invalid_state("Not in playing state!");
                 }
             };
             self.send_to_roon(SpotifyJSEvent::Pause {
@@ -148,8 +147,7 @@ impl PlayerInternal {
             let loader = match mem::replace(&mut self.state, PlayerState::Invalid) {
                 PlayerState::Loading { loader, .. } => loader,
                 _ => {
-                    error!("Not in loading state!");
-                    exit(1);
+                    return self.invalid_state("Not in loading state!");
                 }
             };
            self.state = PlayerState::Loading {
@@ -167,6 +165,7 @@ impl PlayerInternal {
 
     // Spotify told us that the user disconnected, let roon know and update our state;
     fn handle_stop(&mut self) {
+        self.set_preload(PlayerPreload::None);
         match self.state {
             PlayerState::Invalid => {
                 warn!("Called handle_stop from player state Invalid");
@@ -186,11 +185,12 @@ impl PlayerInternal {
                 track_id,
                 ..
             } => {
-                self.state = PlayerState::Stopped;
+                self.set_state(PlayerState::Stopped);
                 self.yet_to_play = true;
                 self.send_to_roon(SpotifyJSEvent::Stop {
                     zone_id:          self.zone_id.clone(),
                 });
+                self.clear_slots(&["play"]);
                 self.send_event(PlayerEvent::Stopped {
                     play_request_id,
                     track_id
@@ -224,7 +224,7 @@ impl PlayerInternal {
                 preload_track = false;
             } else {
                 // we're preloading something else - cancel it.  
-                self.preload = PlayerPreload::None;
+                self.set_preload(PlayerPreload::None);
             }
         }
 
@@ -261,10 +261,12 @@ impl PlayerInternal {
                 let preload = std::mem::replace(&mut self.preload, PlayerPreload::None);
                 if let PlayerPreload::Ready {
                     track_id,
-                    loaded_track,
+                    mut loaded_track,
                     preload_id,
                     ..
                 } = preload {
+                    // Preloads start at 0, keep the requested position (episode resume points)
+                    loaded_track.start_position_ms = position_ms;
                     // let position_pcm = Self::position_ms_to_pcm(position_ms);
                     // XXX Fix stream here with a seek;
                     // XXX Update state!
@@ -272,14 +274,17 @@ impl PlayerInternal {
                     
 
                     info!("Requested track id {:?} was already loaded, setting state to playing", track_id);
+                    metrics::record_preload(true);
                     self.send_to_roon(SpotifyJSEvent::Play {
                         zone_id:          self.zone_id.clone(),
-                        now_playing_info: RoonNowPlaying::new(loaded_track.audio.clone()),
+                        now_playing_info: loaded_track.now_playing.clone(),
                         position_ms:      loaded_track.start_position_ms.clone(),
                         play_request_id,
-                        preload_id:  Some(preload_id.clone())
+                        preload_id:  Some(preload_id.clone()),
+                        stream_url:  self.stream_url(&loaded_track),
+                        replay_gain: self.replay_gain(&loaded_track)
                     });
-                    self.state = PlayerState::Playing {
+                    self.set_state(PlayerState::Playing {
                         track_id,
                         play_request_id,
                         position_ms: loaded_track.start_position_ms.clone(),
@@ -287,11 +292,10 @@ impl PlayerInternal {
                         track:       *loaded_track,
                         suggested_to_preload_next_track: false,
                         preload_id: Some(preload_id)
-                    };
+                    });
                     return;
                 } else {
-                    error!("PlayerInternal handle_command_load: Invalid PlayerState");
-                    exit(1);
+                    return self.invalid_state("PlayerInternal handle_command_load: Invalid PlayerState");
                 }
             } else {
                 info!("Requested track id {:?} does not equal loaded_track_id {:?}, setting up loader", track_id, loaded_track_id);
@@ -328,10 +332,27 @@ impl PlayerInternal {
             None
         };
 
-        self.preload = PlayerPreload::None;
+        self.set_preload(PlayerPreload::None);
 
-        // If we don't have a loader yet, create one from scratch.
-        let loader = loader.unwrap_or_else(|| Box::pin(self.load_track(track_id, position_ms)));
+        let loader: Pin<Box<dyn Future<Output = Result<RoonPlayerLoadedTrack, ()>> + Send>> = match loader {
+            Some(loader) => {
+                metrics::record_preload(true);
+                loader
+            },
+            None => match self.take_recent_track(track_id) {
+                // A recently played track is reused as is, it was never up for preloading
+                Some(mut track) => {
+                    info!("Requested track id {:?} was recently loaded, reusing it", track_id);
+                    track.start_position_ms = position_ms;
+                    Box::pin(future::ready(Ok(track)))
+                },
+                // Otherwise create a loader from scratch
+                None => {
+                    metrics::record_preload(false);
+                    Box::pin(self.load_track(track_id, position_ms))
+                }
+            }
+        };
         //let loader = Box::pin(self.load_track(track_id, position_ms));
 
         let mut prev_track_id = None; 
@@ -341,14 +362,14 @@ impl PlayerInternal {
             prev_track_id = Some(track_id);
         }
         // Set ourselves to a loading state.
-        self.state = PlayerState::Loading {
+        self.set_state(PlayerState::Loading {
             track_id,
             play_request_id,
             start_playback: play,
             loader,
             prev_track_id,
             preload_id
-        };
+        });
     }
 
     // Spotify told us to seek, let roon know
@@ -375,6 +396,7 @@ impl PlayerInternal {
     }
 
     fn handle_volume_set(&mut self, volume: u16) {
+        self.volume = Some(volume);
        if let PlayerState::Playing {
            ..
        } | PlayerState::Paused {
@@ -1,8 +1,10 @@
-use crate::zone::{RoonMessage};
-use std::process::exit;
+use crate::zone::{RoonMessage, bitrate_from_kbps};
 use std::mem;
 use super::*;
 
+// Consecutive failures before we stop working through a failing playlist
+const MAX_PLAYBACK_ERRORS: u32 = 5;
+
 impl PlayerInternal {
 
     pub fn handle_roon_command(&mut self, msg: RoonMessage) {
@@ -12,7 +14,7 @@ impl PlayerInternal {
             RoonMessage::Paused  {..}        => self.handle_roon_paused(),
             RoonMessage::Unpaused {..}       => self.handle_roon_unpaused(),
             RoonMessage::Time    {..}        => self.handle_roon_time(msg),
-            RoonMessage::Seeked  {..}        => (),
+            RoonMessage::Seeked  {..}        => self.handle_roon_seeked(msg),
             RoonMessage::NextTrack{..}       => self.handle_roon_next_track(),
             RoonMessage::PreviousTrack{..}   => self.handle_roon_previous_track(),
             RoonMessage::Stopped {..}        => self.handle_roon_stopped(),
@@ -20,7 +22,8 @@ impl PlayerInternal {
             RoonMessage::OnToNext {..}       => self.handle_roon_on_to_next(),
             RoonMessage::Volume {..}         => self.handle_roon_volume(msg),
             RoonMessage::RenameZone {..}     => self.handle_roon_rename_zone(msg),
-            RoonMessage::Error {..}          => (),
+            RoonMessage::SetBitrate {..}     => self.handle_roon_set_bitrate(msg),
+            RoonMessage::Error {..}          => self.handle_roon_error(msg),
             _ => ()
         }
     }
@@ -47,8 +50,7 @@ impl PlayerInternal {
                 };
             },
             _ => {
-                error!("Called playing_to_pause from state other than playing");
-                exit(1);
+                return self.invalid_state("Called playing_to_pause from state other than playing");
             }
         };
     }
@@ -76,14 +78,16 @@ impl PlayerInternal {
                 };
             },
             _ => {
-                error!("Called paused_to_playing from state other than Paused");
-                exit(1);
+                return self.invalid_state("Called paused_to_playing from state other than Paused");
             }
         };
     }
 
 
     fn handle_roon_playing(&mut self) {
+        // Roon got our stream playing, failures before this one are behind us
+        self.playback_errors = 0;
+        self.retried_track   = None;
         // Already playing, no state change just tell spotify
         if let PlayerState::Playing {
             track_id,
@@ -197,48 +201,58 @@ impl PlayerInternal {
     fn handle_roon_time(&mut self, msg: RoonMessage) {
         // We are already playing, roon is just telling us where we
         // are at in the track. Update our state and relay to spotify
-
         if let RoonMessage::Time { seek_position_ms, track_id, .. } = msg {
-            
-            if let PlayerState::Playing {
+            self.set_roon_position(Some(track_id), seek_position_ms, "time");
+        }
+    }
+
+    fn handle_roon_seeked(&mut self, msg: RoonMessage) {
+        // Scrubbed in roon, spirc broadcasts the new position to the spotify clients
+        // right away instead of on the next time tick
+        if let RoonMessage::Seeked { seek_position_ms, track_id, .. } = msg {
+            self.set_roon_position(track_id, seek_position_ms, "seeked");
+        }
+    }
+
+    fn set_roon_position(&mut self, track_id: Option<String>, seek_position_ms: u32, kind: &str) {
+        if let PlayerState::Playing {
+            track_id: playing_track_id,
+            play_request_id,
+            ref mut position_ms,
+            duration_ms,
+            ..
+        } = self.state {
+            if track_id.as_ref().map_or(false, |id| *id != playing_track_id.to_uri().unwrap()) {
+                warn!("Got roon {} message for stale track id, ignoring, {:?} != {:?}", kind, track_id, playing_track_id);
+                return;
+            }
+            *position_ms = seek_position_ms;
+            self.send_event(PlayerEvent::Playing {
                 track_id: playing_track_id,
                 play_request_id,
-                ref mut position_ms,
+                position_ms: seek_position_ms,
                 duration_ms,
-                ..
-            } = self.state {
-                if track_id != playing_track_id.to_uri().unwrap() {
-                    warn!("Got roon time message for stale track id, ignoring, {} != {:?}", track_id, playing_track_id);
-                    return;
-                }
-                *position_ms = seek_position_ms;
-                self.send_event(PlayerEvent::Playing {
-                    track_id: playing_track_id,
-                    play_request_id,
-                    position_ms: seek_position_ms,
-                    duration_ms,
-                });
-            } else if let PlayerState::Paused {
+            });
+        } else if let PlayerState::Paused {
+            track_id: paused_track_id,
+            play_request_id,
+            ref mut position_ms,
+            duration_ms,
+            ..
+        } = self.state{
+            if track_id.as_ref().map_or(false, |id| *id != paused_track_id.to_uri().unwrap()) {
+                warn!("Got roon {} message for stale track id, ignoring, {:?} != {:?}", kind, track_id, paused_track_id);
+                return;
+            }
+            *position_ms = seek_position_ms;
+            self.send_event(PlayerEvent::Paused {
                 track_id: paused_track_id,
                 play_request_id,
-                ref mut position_ms,
+                position_ms: seek_position_ms,
                 duration_ms,
-                ..
-            } = self.state{
-                if track_id != paused_track_id.to_uri().unwrap() {
-                    warn!("Got roon time message for stale track id, ignoring, {} != {:?}", track_id, paused_track_id);
-                    return;
-                }
-                *position_ms = seek_position_ms;
-                self.send_event(PlayerEvent::Paused {
-                    track_id: paused_track_id,
-                    play_request_id,
-                    position_ms: seek_position_ms,
-                    duration_ms,
-                });
-            } else {
-                warn!("Got roon time message while not in playing/paused state");
-            }
+            });
+        } else {
+            warn!("Got roon {} message while not in playing/paused state", kind);
         }
     }
 
@@ -275,8 +289,7 @@ impl PlayerInternal {
             let loader = match mem::replace(&mut self.state, PlayerState::Invalid) {
                 PlayerState::Loading { loader, .. } => loader,
                 _ => {
-                    error!("Not in loading state!");
-                    exit(1);
+                    return self.invalid_state("Not in loading state!");
                 }
             };
             self.state = PlayerState::Loading {
@@ -360,18 +373,134 @@ impl PlayerInternal {
             warn!("Got roon prev track message while not in playing or paused state");
         }
     }
+    fn handle_roon_error(&mut self, msg: RoonMessage) {
+        let (reason, slot) = match msg {
+            RoonMessage::Error { reason, slot, .. } => (reason, slot),
+            _ => return
+        };
+        warn!("Roon failed to play the {} slot for zone {}: {}", slot, self.zone_id, reason);
+        self.playback_errors += 1;
+        if self.playback_errors >= MAX_PLAYBACK_ERRORS {
+            return self.pause_after_errors(reason);
+        }
+        if slot == "queue" {
+            return self.handle_roon_queue_error();
+        }
+        if let PlayerState::Playing {
+            track_id,
+            play_request_id,
+            position_ms,
+            ..
+        } | PlayerState::Paused {
+            track_id,
+            play_request_id,
+            position_ms,
+            ..
+        } = self.state {
+            if self.retried_track == Some(track_id) {
+                // Failed again from a fresh file, let spirc move on
+                info!("Skipping {:?} after a failed retry", track_id);
+                self.retried_track = None;
+                self.send_event(PlayerEvent::EndOfTrack {
+                    track_id,
+                    play_request_id,
+                });
+            } else {
+                // Reopen the file, the failed one may hold a broken download. It is dropped
+                // instead of kept with the recent tracks so nothing serves it again.
+                info!("Retrying {:?} from {}ms", track_id, position_ms);
+                self.retried_track = Some(track_id);
+                let loader = self.load_track(track_id, position_ms);
+                self.state = PlayerState::Loading {
+                    track_id,
+                    play_request_id,
+                    start_playback: true,
+                    loader:         Box::pin(loader),
+                    prev_track_id:  None,
+                    preload_id:     None,
+                };
+            }
+        } else {
+            warn!("Got roon error message while not in playing or paused state");
+        }
+    }
+
+    // Roon couldn't queue the preloaded stream, load it once more before letting
+    // spotify load it normally when the current track ends
+    fn handle_roon_queue_error(&mut self) {
+        let track_id = match self.preload {
+            PlayerPreload::Ready { track_id, .. } => track_id,
+            _ => return
+        };
+        if self.retried_track == Some(track_id) {
+            self.retried_track = None;
+            self.set_preload(PlayerPreload::None);
+            return;
+        }
+        self.retried_track = Some(track_id);
+        // Replaced without retiring, the broken stream must not be served again
+        let loader = self.load_track(track_id, 0);
+        self.preload = PlayerPreload::Loading {
+            track_id,
+            loader:     Box::pin(loader),
+            preload_id: self.preload_id_generator.get()
+        };
+    }
+
+    fn pause_after_errors(&mut self, reason: String) {
+        let message = format!("Roon failed to play {} tracks in a row, pausing: {}", self.playback_errors, reason);
+        // A resume from spotify starts counting again
+        self.playback_errors = 0;
+        self.retried_track   = None;
+        self.set_preload(PlayerPreload::None);
+        if let PlayerState::Playing {
+            track_id,
+            play_request_id,
+            position_ms,
+            duration_ms,
+            ..
+        } = self.state {
+            self.playing_to_paused();
+            self.send_event(PlayerEvent::Paused {
+                track_id,
+                play_request_id,
+                position_ms,
+                duration_ms,
+            });
+        }
+        // Roon dropped the failed slot, resuming has to send the stream again
+        self.yet_to_play = true;
+        error!("{}", message);
+        // Nothing restarts the zone, the user has to resume playback
+        self.send_to_roon(SpotifyJSEvent::ZoneError {
+            zone_id:     self.zone_id.clone(),
+            kind:        ZoneErrorKind::Player,
+            message,
+            recoverable: false
+        });
+    }
+
     fn handle_roon_volume(&mut self, msg: RoonMessage) {
         let volume = match msg {
             RoonMessage::Volume { volume, .. } => volume,
             _ => {
-                error!("Got something other than volume message in roon volume handler");
-                exit(1);
+                warn!("Got something other than volume message in roon volume handler");
+                return;
             }
         };
         self.send_event(PlayerEvent::VolumeSet {
             volume
         })
     }
+    fn handle_roon_set_bitrate(&mut self, msg: RoonMessage) {
+        // Only affects tracks loaded from here on, the current stream keeps its format
+        if let RoonMessage::SetBitrate { bitrate, .. } = msg {
+            if let Some(bitrate) = bitrate_from_kbps(bitrate) {
+                info!("Setting bitrate to {:?} for zone {}", bitrate, self.zone_id);
+                self.config.bitrate = bitrate;
+            }
+        }
+    }
     fn handle_roon_rename_zone(&mut self, _msg: RoonMessage) {
         return;
         /* Viable to send from here, but better to send from spirc, so pass through for now
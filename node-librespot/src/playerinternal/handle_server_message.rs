@@ -2,102 +2,121 @@ use crate::server::{ServerMessage,ServerReply};
 use super::*;
 
 impl PlayerInternal {
+    // Replies are sent on oneshot channels, a dropped receiver just means the
+    // http client went away
     pub fn handle_server_message(&mut self, msg: ServerMessage) {
         match msg {
             ServerMessage::TrackInfo {
+                zone_id,
                 track_id,
                 responder,
-                ..
             } => {
-                match &mut self.state {
-                    PlayerState::Playing { track, .. } |
-                    PlayerState::Paused  { track, .. }
-                    => {
-                        if track_id == track.audio.id.clone().to_uri().unwrap() {
-                            responder.send(ServerReply::TrackInfo { 
-                                file_size: track.audio_file.len(),
-                                track_id: track.audio.id.clone().to_uri().unwrap()
-                            }).unwrap();
-                            return;
-                        }
-                    },
-                    _ => ()
-                }
-
-                match &mut self.preload {
-                    PlayerPreload::Ready {
-                        loaded_track,
-                        ..
-                    } => {
-                        let track = &mut *loaded_track;
-                        if track_id == track.audio.id.clone().to_uri().unwrap() {
-                            responder.send(ServerReply::TrackInfo { 
-                                file_size: track.audio_file.len(),
-                                track_id: track.audio.id.clone().to_uri().unwrap()
-                            }).unwrap();
-                        } else {
-                            // Bad track id, not in current state or preloaded state
-                            responder.send(ServerReply::Busy).unwrap();
-                        }
-                    }
-                    _ => {
-                        // Preloader not ready
-                        responder.send(ServerReply::Busy).unwrap();
-                    }
+                if let Some(track) = self.find_loaded_track(&track_id) {
+                    let _ = responder.send(ServerReply::TrackInfo {
+                        file_size: track.audio_file.len(),
+                        format:    track.format,
+                        file_id:   track.file_id,
+                        track_id
+                    });
+                } else if self.is_loading(&track_id) {
+                    // Answered once the loader resolves, the server times out on its own
+                    self.parked_requests.push(ServerMessage::TrackInfo {
+                        zone_id,
+                        track_id,
+                        responder
+                    });
+                } else {
+                    let _ = responder.send(ServerReply::NotCurrent);
                 }
             },
             ServerMessage::TrackRead {
                 track_id,
                 start,
-                out,
+                end,
                 responder,
                 ..
             } => {
-                match &mut self.state {
-                    PlayerState::Playing { track, .. } |
-                    PlayerState::Paused  { track, .. }
-                    => {
-                        if track_id == track.audio.id.clone().to_uri().unwrap() {
-                            track.audio_file.seek(SeekFrom::Start(start as u64)).unwrap();
-                            let file_size = track.audio_file.len();
-                            let read_len  = track.audio_file.read(&mut out.lock().unwrap()).unwrap();
-                            responder.send(ServerReply::TrackRead { 
-                                read_len,
-                                file_size,
-                                track_id: track.audio.id.clone().to_uri().unwrap()
-                            }).unwrap();
-                            return;
+                let reply = match self.find_loaded_track(&track_id) {
+                    Some(track) => match read_range(track, start, end) {
+                        Ok(data) => ServerReply::TrackRead {
+                            data,
+                            file_size: track.audio_file.len(),
+                            track_id
+                        },
+                        Err(e) => {
+                            error!("Error reading {} at {}: {}", track_id, start, e);
+                            ServerReply::ReadError
                         }
                     },
-                    _ => ()
-                }
+                    None => ServerReply::NotCurrent
+                };
+                let _ = responder.send(reply);
+            },
+            ServerMessage::PlayerStatus {
+                mut zone,
+                responder
+            } => {
+                zone.player = Some(self.status());
+                let _ = responder.send(ServerReply::ZoneStatus(zone));
+            },
+            // Answered by devices and the zone, never forwarded here
+            ServerMessage::Zones { responder } |
+            ServerMessage::ZoneStatus { responder, .. } => {
+                let _ = responder.send(ServerReply::UnknownZone);
+            }
+        }
+    }
 
-                match &mut self.preload {
-                    PlayerPreload::Ready {
-                        loaded_track,
-                        ..
-                    } => {
-                        let track = &mut *loaded_track;
-                        if track_id == track.audio.id.clone().to_uri().unwrap() {
-                            track.audio_file.seek(SeekFrom::Start(start as u64)).unwrap();
-                            let file_size = track.audio_file.len();
-                            let read_len  = track.audio_file.read(&mut out.lock().unwrap()).unwrap();
-                            responder.send(ServerReply::TrackRead { 
-                                read_len,
-                                file_size,
-                                track_id: track.audio.id.clone().to_uri().unwrap()
-                            }).unwrap();
-                        } else {
-                            // Bad track id, not in current state or preloaded state
-                            responder.send(ServerReply::Busy).unwrap();
-                        }
-                    }
-                    _ => {
-                        // Preloader not ready
-                        responder.send(ServerReply::Busy).unwrap();
-                    }
+    fn is_loading(&self, track_id: &str) -> bool {
+        if let PlayerState::Loading { track_id: loading_track_id, .. } = self.state {
+            if loading_track_id.to_uri().unwrap() == track_id {
+                return true;
+            }
+        }
+        if let PlayerPreload::Loading { track_id: loading_track_id, .. } = self.preload {
+            if loading_track_id.to_uri().unwrap() == track_id {
+                return true;
+            }
+        }
+        false
+    }
+
+    // Requests still waiting on a loader are parked again, the rest are answered
+    pub fn resume_parked_requests(&mut self) {
+        let parked = std::mem::take(&mut self.parked_requests);
+        for msg in parked {
+            if let ServerMessage::TrackInfo { responder, .. } = &msg {
+                if responder.is_closed() {
+                    continue; // Server gave up waiting
                 }
             }
+            self.handle_server_message(msg);
+        }
+    }
+
+    // Current track first, then the preloaded one, then recently played ones
+    fn find_loaded_track(&mut self, track_id: &str) -> Option<&mut RoonPlayerLoadedTrack> {
+        if let PlayerState::Playing { track, .. } |
+               PlayerState::Paused  { track, .. } = &mut self.state {
+            if track.audio.id.to_uri().unwrap() == track_id {
+                return Some(track);
+            }
         }
+        if let PlayerPreload::Ready { loaded_track, .. } = &mut self.preload {
+            if loaded_track.audio.id.to_uri().unwrap() == track_id {
+                return Some(&mut **loaded_track);
+            }
+        }
+        self.recent_tracks
+            .iter_mut()
+            .find(|t| t.audio.id.to_uri().unwrap() == track_id)
     }
 }
+
+fn read_range(track: &mut RoonPlayerLoadedTrack, start: usize, end: usize) -> io::Result<Vec<u8>> {
+    let mut data = vec![0u8; end.saturating_sub(start)];
+    track.audio_file.seek(SeekFrom::Start(start as u64))?;
+    let read_len = track.audio_file.read(&mut data)?;
+    data.truncate(read_len);
+    Ok(data)
+}
@@ -1,11 +1,12 @@
 // Port from librespot player.rs
-use std::process::exit;
 use std::future::Future;
 use std::io::{self, Read, Seek, SeekFrom};
 use std::pin::Pin;
 use std::mem;
+use std::collections::VecDeque;
 use std::task::{Context, Poll};
 use std::sync::{Mutex,Arc};
+use std::time::Instant;
 
 use futures_util::stream::futures_unordered::FuturesUnordered;
 use futures_util::{future, StreamExt, TryFutureExt};
@@ -14,27 +15,70 @@ use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
 
 use crate::player::*;
 use crate::server::{ServerMessage};
-use crate::zone::{SpotifyJSEvent, RoonNowPlaying, RoonMessage};
+use crate::zone::{SpotifyJSEvent, RoonNowPlaying, RoonMessage, RoonReplayGain, ZoneErrorKind, PlayerStatus};
+use byteorder::{LittleEndian, ReadBytesExt};
+use crate::cache::{self, AudioCacheLimiter};
+use crate::metrics;
+use crate::stream_token::{StreamSigner};
+use crate::formats::{self, SPOTIFY_OGG_HEADER_END};
 
 use librespot::core::util::SeqGenerator;
 use librespot::playback::player::{PlayerEvent};
-use librespot::audio::{AudioFile, AudioDecrypt};
-use librespot::playback::config::{Bitrate, PlayerConfig};
+use librespot::audio::{AudioFile, AudioDecrypt, StreamLoaderController};
+use librespot::playback::config::{PlayerConfig};
 use librespot::core::session::Session;
-use librespot::core::spotify_id::SpotifyId;
+use librespot::core::spotify_id::{FileId, SpotifyId, SpotifyAudioType};
 use librespot::metadata::{AudioItem, FileFormat};
+use librespot::protocol;
+use protobuf::Message;
 
-const PRELOAD_NEXT_TRACK_BEFORE_END_DURATION_MS: u32 = 30000;
+const SPOTIFY_NORMALIZATION_HEADER_START_OFFSET: u64 = 144;
+// Tracks kept around after they stop being current or preloaded
+const RECENT_TRACKS_MAX_COUNT: usize = 4;
+const RECENT_TRACKS_MAX_BYTES: usize = 64 * 1024 * 1024;
 
 pub struct RoonPlayerLoadedTrack {
     audio_file:        Subfile<AudioDecrypt<AudioFile>>,
+    stream_loader_controller: StreamLoaderController,
+    download:          DownloadProgress,
     audio:             AudioItem,
+    format:            FileFormat,
+    file_id:           FileId,
+    now_playing:       RoonNowPlaying,
+    normalisation:     Option<NormalisationData>,
     start_position_ms: u32 ,
 }
 
+// Stored in the header Subfile skips over
+#[derive(Debug, Clone, Copy)]
+pub struct NormalisationData {
+    track_gain_db: f32,
+    track_peak:    f32,
+    album_gain_db: f32,
+    album_peak:    f32,
+}
+
+impl NormalisationData {
+    fn parse_from_file<T: Read + Seek>(file: &mut T) -> io::Result<NormalisationData> {
+        file.seek(SeekFrom::Start(SPOTIFY_NORMALIZATION_HEADER_START_OFFSET))?;
+        let track_gain_db = file.read_f32::<LittleEndian>()?;
+        let track_peak    = file.read_f32::<LittleEndian>()?;
+        let album_gain_db = file.read_f32::<LittleEndian>()?;
+        let album_peak    = file.read_f32::<LittleEndian>()?;
+        Ok(NormalisationData {
+            track_gain_db,
+            track_peak,
+            album_gain_db,
+            album_peak,
+        })
+    }
+}
+
 struct PlayerTrackLoader {
     session: Session,
     config: PlayerConfig,
+    formats: Vec<FileFormat>,
+    audio_cache_limiter: Option<Arc<AudioCacheLimiter>>,
 }
 
 impl PlayerTrackLoader {
@@ -57,22 +101,66 @@ impl PlayerTrackLoader {
         }
     }
 
-    fn stream_data_rate(&self, format: FileFormat) -> usize {
-        match format {
-            FileFormat::OGG_VORBIS_96 => 12 * 1024,
-            FileFormat::OGG_VORBIS_160 => 20 * 1024,
-            FileFormat::OGG_VORBIS_320 => 40 * 1024,
-            FileFormat::MP3_256 => 32 * 1024,
-            FileFormat::MP3_320 => 40 * 1024,
-            FileFormat::MP3_160 => 20 * 1024,
-            FileFormat::MP3_96 => 12 * 1024,
-            FileFormat::MP3_160_ENC => 20 * 1024,
-            FileFormat::MP4_128_DUAL => 16 * 1024,
-            FileFormat::OTHER3 => 40 * 1024, // better some high guess than nothing
-            FileFormat::AAC_160 => 20 * 1024,
-            FileFormat::AAC_320 => 40 * 1024,
-            FileFormat::MP4_128 => 16 * 1024,
-            FileFormat::OTHER5 => 40 * 1024, // better some high guess than nothing
+    // AudioItem only has the basics, the rest comes from the raw metadata
+    async fn now_playing(&self, audio: &AudioItem) -> RoonNowPlaying {
+        let mut now_playing = RoonNowPlaying::new(audio.clone());
+        let kind = match audio.id.audio_type {
+            SpotifyAudioType::Track   => "track",
+            SpotifyAudioType::Podcast => "episode",
+            _ => return now_playing
+        };
+        let uri = match audio.id.to_base16() {
+            Ok(id) => format!("hm://metadata/3/{}/{}", kind, id),
+            _ => return now_playing
+        };
+        let response = match self.session.mercury().get(uri).await {
+            Ok(response) => response,
+            Err(e) => {
+                warn!("Unable to load metadata for <{}>: {:?}", audio.uri, e);
+                return now_playing;
+            }
+        };
+        let data = match response.payload.first() {
+            Some(data) => data,
+            None => return now_playing
+        };
+        match audio.id.audio_type {
+            SpotifyAudioType::Track => match protocol::metadata::Track::parse_from_bytes(data) {
+                Ok(track) => now_playing.add_track_metadata(&track),
+                Err(e) => warn!("Unable to parse track metadata: {:?}", e)
+            },
+            _ => match protocol::metadata::Episode::parse_from_bytes(data) {
+                Ok(episode) => {
+                    now_playing.add_episode_metadata(&episode);
+                    // The show embedded in an episode has no publisher or artwork
+                    if let Some(show) = self.show_metadata(episode.get_show().get_gid()).await {
+                        now_playing.add_show_metadata(&show);
+                    }
+                },
+                Err(e) => warn!("Unable to parse episode metadata: {:?}", e)
+            }
+        }
+        now_playing
+    }
+
+    async fn show_metadata(&self, gid: &[u8]) -> Option<protocol::metadata::Show> {
+        if gid.is_empty() {
+            return None;
+        }
+        let uri = format!("hm://metadata/3/show/{}", hex::encode(gid));
+        let response = match self.session.mercury().get(uri).await {
+            Ok(response) => response,
+            Err(e) => {
+                warn!("Unable to load show metadata: {:?}", e);
+                return None;
+            }
+        };
+        match protocol::metadata::Show::parse_from_bytes(response.payload.first()?) {
+            Ok(show) => Some(show),
+            Err(e) => {
+                warn!("Unable to parse show metadata: {:?}", e);
+                None
+            }
         }
     }
 
@@ -110,28 +198,17 @@ impl PlayerTrackLoader {
         }
 
         //let duration_ms = audio.duration as u32;
+        let now_playing = self.now_playing(&audio).await;
 
-        // (Most) podcasts seem to support only 96 bit Vorbis, so fall back to it
-        let formats = match self.config.bitrate {
-            Bitrate::Bitrate96 => [
-                FileFormat::OGG_VORBIS_96,
-                FileFormat::OGG_VORBIS_160,
-                FileFormat::OGG_VORBIS_320,
-            ],
-            Bitrate::Bitrate160 => [
-                FileFormat::OGG_VORBIS_160,
-                FileFormat::OGG_VORBIS_96,
-                FileFormat::OGG_VORBIS_320,
-            ],
-            Bitrate::Bitrate320 => [
-                FileFormat::OGG_VORBIS_320,
-                FileFormat::OGG_VORBIS_160,
-                FileFormat::OGG_VORBIS_96,
-            ],
+        // An empty preference list keeps the bitrate based Vorbis selection
+        let preferred = if self.formats.is_empty() {
+            formats::default_formats(self.config.bitrate, audio.id.audio_type == SpotifyAudioType::Podcast)
+        } else {
+            self.formats.clone()
         };
 
         let (format, file_id) =
-            match formats
+            match preferred
                 .iter()
                 .find_map(|format| match audio.files.get(format) {
                     Some(&file_id) => Some((*format, file_id)),
@@ -144,12 +221,14 @@ impl PlayerTrackLoader {
                 }
             };
 
-        let bytes_per_second = self.stream_data_rate(format);
+        let bytes_per_second = stream_data_rate(format);
         let play_from_beginning = position_ms == 0;
+        let is_ogg = formats::is_ogg_vorbis(format);
 
         // This is only a loop to be able to reload the file if an error occurred
         // while opening a cached file.
         loop {
+            let opened_at = Instant::now();
             let encrypted_file = AudioFile::open(
                 &self.session,
                 file_id,
@@ -165,10 +244,16 @@ impl PlayerTrackLoader {
                     return None;
                 }
             };
-            //let is_cached = encrypted_file.is_cached();
+            let is_cached = encrypted_file.is_cached();
+            cache::record_audio_file(is_cached);
 
             let stream_loader_controller = encrypted_file.get_stream_loader_controller();
 
+            // Make room for the file this download is about to add
+            if let (false, Some(limiter)) = (is_cached, &self.audio_cache_limiter) {
+                limiter.make_room(stream_loader_controller.len() as u64);
+            }
+
             if play_from_beginning {
                 // No need to seek -> we stream from the beginning
                 stream_loader_controller.set_stream_mode();
@@ -185,11 +270,46 @@ impl PlayerTrackLoader {
                 }
             };
 
-            let decrypted_file = AudioDecrypt::new(key, encrypted_file);
-            let audio_file = Subfile::new(decrypted_file, 0xa7);
+            let mut decrypted_file = AudioDecrypt::new(key, encrypted_file);
+
+            // A cached file that does not start with an Ogg page is corrupt, remove it
+            // from the cache and download it again
+            if is_cached && is_ogg && !has_ogg_header(&mut decrypted_file) {
+                warn!("Cached file for <{}> is corrupt, reloading", audio.name);
+                match self.session.cache() {
+                    Some(cache) => {
+                        if let Err(e) = cache.remove_file(file_id) {
+                            error!("Unable to remove corrupt cached file: {:?}", e);
+                            return None;
+                        }
+                    },
+                    None => return None
+                }
+                continue;
+            }
+
+            // Only Spotify's Ogg files carry the extra header
+            let mut normalisation = None;
+            if is_ogg {
+                normalisation = match NormalisationData::parse_from_file(&mut decrypted_file) {
+                    Ok(data) => Some(data),
+                    Err(e) => {
+                        warn!("Unable to read normalisation data for <{}>: {}", audio.name, e);
+                        None
+                    }
+                };
+            }
+
+            let audio_file = Subfile::new(decrypted_file, formats::header_offset(format));
             return Some(RoonPlayerLoadedTrack {
                 audio_file, // File handle
+                stream_loader_controller,
+                download: DownloadProgress::new(opened_at, play_from_beginning && !is_cached),
                 audio,      // Track metadata
+                format,
+                file_id,
+                now_playing,
+                normalisation,
                 start_position_ms: position_ms
             });
         }
@@ -212,8 +332,22 @@ pub struct PlayerInternal {
     pub player_server_rx: Arc<Mutex<UnboundedReceiver<ServerMessage>>>,
     pub player_roon_rx: Arc<Mutex<UnboundedReceiver<RoonMessage>>>,
     pub js_tx: Arc<Mutex<UnboundedSender<SpotifyJSEvent>>>,
+    pub stream_signer: StreamSigner,
+    // Preferred formats in order, empty falls back to the configured bitrate
+    pub formats: Vec<FileFormat>,
+    pub audio_cache_limiter: Option<Arc<AudioCacheLimiter>>,
     pub zone_id: String,
-    pub yet_to_play: bool
+    pub yet_to_play: bool,
+    // Http requests for tracks that are still loading
+    pub parked_requests: Vec<ServerMessage>,
+    // Most recent first, roon may still request these after quick skips
+    pub recent_tracks: VecDeque<RoonPlayerLoadedTrack>,
+    pub volume: Option<u16>,
+    pub preload_timer: Arc<Mutex<PreloadTimer>>,
+    // Roon playback failures since it last played one of our streams
+    pub playback_errors: u32,
+    // Track already reloaded once after a failure, skipped if it fails again
+    pub retried_track: Option<SpotifyId>
 }
 
 impl Future for PlayerInternal {
@@ -282,9 +416,11 @@ impl Future for PlayerInternal {
                             self.send_to_roon(SpotifyJSEvent::Play {
                                 zone_id,
                                 play_request_id,
-                                now_playing_info: RoonNowPlaying::new(loaded_track.audio.clone()),
+                                now_playing_info: loaded_track.now_playing.clone(),
                                 position_ms:      loaded_track.start_position_ms.clone(),
                                 preload_id:       preload_id.clone(),
+                                stream_url:       self.stream_url(&loaded_track),
+                                replay_gain:      self.replay_gain(&loaded_track),
                             });
                             self.send_event(PlayerEvent::Loading {
                                 track_id,
@@ -336,8 +472,10 @@ impl Future for PlayerInternal {
                         let zone_id = self.zone_id.clone();
                         self.send_to_roon(SpotifyJSEvent::Preload {
                             zone_id,
-                            now_playing_info: RoonNowPlaying::new(loaded_track.audio.clone()),
-                            preload_id: preload_id.clone()
+                            now_playing_info: loaded_track.now_playing.clone(),
+                            preload_id: preload_id.clone(),
+                            stream_url: self.stream_url(&loaded_track),
+                            replay_gain: self.replay_gain(&loaded_track)
                         });
                         self.preload = PlayerPreload::Ready {
                             loaded_track: Box::new(loaded_track),
@@ -351,41 +489,13 @@ impl Future for PlayerInternal {
                     Poll::Pending => (),
                 }
             }
-            // This is the old logic for when to start downloading next track from spotify
-            // If it is time to load the next track, let spirc know and it will call preload on the
-            // this player with the next track id
-            if let PlayerState::Playing {
-                track_id,
-                play_request_id,
-                duration_ms,
-                position_ms,
-                ref mut suggested_to_preload_next_track,
-                ..
-            }
-            | PlayerState::Paused {
-                track_id,
-                play_request_id,
-                duration_ms,
-                position_ms,
-                ref mut suggested_to_preload_next_track,
-                ..
-            } = self.state
-            {
-                let mut time_to_end = 0;
-                if position_ms < duration_ms {
-                    time_to_end = duration_ms - position_ms;
-                }
-                // XXX look into range_to_end_available in original librespot player
-                if (!*suggested_to_preload_next_track) &&
-                    time_to_end < PRELOAD_NEXT_TRACK_BEFORE_END_DURATION_MS {
-                    *suggested_to_preload_next_track = true;
-                    self.send_event(PlayerEvent::TimeToPreloadNextTrack {
-                        track_id,
-                        play_request_id,
-                    });
-                }
+            // Answer requests that were waiting on a loader that has since resolved
+            if !self.parked_requests.is_empty() {
+                self.resume_parked_requests();
             }
 
+            self.suggest_preload();
+
             // Kill loop once session ends
             if self.session.is_invalid() {
                 return Poll::Ready(());
@@ -410,6 +520,112 @@ impl PlayerInternal {
         self.js_tx.lock().unwrap().send(evt).unwrap();
     }
 
+    // Reset this zone's player instead of taking down the process
+    fn invalid_state(&mut self, message: &str) {
+        error!("{}", message);
+        self.send_to_roon(SpotifyJSEvent::ZoneError {
+            zone_id:     self.zone_id.clone(),
+            kind:        ZoneErrorKind::Player,
+            message:     message.to_string(),
+            recoverable: false
+        });
+        self.state       = PlayerState::Stopped;
+        self.yet_to_play = true;
+        self.send_to_roon(SpotifyJSEvent::Stop {
+            zone_id: self.zone_id.clone(),
+        });
+    }
+
+    fn replay_gain(&self, track: &RoonPlayerLoadedTrack) -> Option<RoonReplayGain> {
+        let data = track.normalisation?;
+        Some(if self.auto_normalise_as_album {
+            RoonReplayGain { gain_db: data.album_gain_db, peak: data.album_peak, album: true }
+        } else {
+            RoonReplayGain { gain_db: data.track_gain_db, peak: data.track_peak, album: false }
+        })
+    }
+
+    pub fn status(&self) -> PlayerStatus {
+        let (state, track_id, now_playing, position_ms, duration_ms) = match &self.state {
+            PlayerState::Invalid => ("invalid", None, None, None, None),
+            PlayerState::Stopped => ("stopped", None, None, None, None),
+            PlayerState::Loading { track_id, .. } => ("loading", Some(*track_id), None, None, None),
+            PlayerState::Playing { track_id, track, position_ms, duration_ms, .. } =>
+                ("playing", Some(*track_id), Some(track.now_playing.clone()), Some(*position_ms), Some(*duration_ms)),
+            PlayerState::Paused { track_id, track, position_ms, duration_ms, .. } =>
+                ("paused", Some(*track_id), Some(track.now_playing.clone()), Some(*position_ms), Some(*duration_ms)),
+        };
+        let (preload, preload_track_id) = match &self.preload {
+            PlayerPreload::None                  => ("none", None),
+            PlayerPreload::Loading { track_id, .. } => ("loading", Some(*track_id)),
+            PlayerPreload::Ready { track_id, .. }   => ("ready", Some(*track_id)),
+        };
+        PlayerStatus {
+            state:            state.to_string(),
+            track_id:         track_id.and_then(|id| id.to_uri().ok()),
+            now_playing,
+            position_ms,
+            duration_ms,
+            preload:          preload.to_string(),
+            preload_track_id: preload_track_id.and_then(|id| id.to_uri().ok()),
+            volume:           self.volume,
+            recent_tracks:    self.recent_tracks.len(),
+            parked_requests:  self.parked_requests.len(),
+            preload_timing:   self.preload_timing()
+        }
+    }
+
+    fn stream_url(&self, track: &RoonPlayerLoadedTrack) -> String {
+        self.stream_signer.stream_url(&self.zone_id, &track.now_playing.track_id)
+    }
+
+    // Replace the player state, keeping the outgoing track for the http server
+    fn set_state(&mut self, state: PlayerState) {
+        match mem::replace(&mut self.state, state) {
+            PlayerState::Playing { track, .. } |
+            PlayerState::Paused  { track, .. } => self.retire_track(track),
+            _ => ()
+        }
+    }
+
+    fn set_preload(&mut self, preload: PlayerPreload) {
+        if let PlayerPreload::Ready { loaded_track, .. } = mem::replace(&mut self.preload, preload) {
+            // Roon queued this stream once it was ready, drop it before it plays the wrong track
+            self.clear_slots(&["queue"]);
+            self.retire_track(*loaded_track);
+        }
+    }
+
+    fn clear_slots(&self, slots: &[&str]) {
+        self.send_to_roon(SpotifyJSEvent::Clear {
+            zone_id: self.zone_id.clone(),
+            slots:   slots.iter().map(|slot| slot.to_string()).collect()
+        });
+    }
+
+    fn retire_track(&mut self, mut track: RoonPlayerLoadedTrack) {
+        let uri = track.audio.id.to_uri().unwrap();
+        self.recent_tracks.retain(|t| t.audio.id.to_uri().unwrap() != uri);
+        let mut total_bytes = track.audio_file.len();
+        self.recent_tracks.push_front(track);
+
+        let mut keep = 1;
+        for t in self.recent_tracks.iter_mut().skip(1) {
+            let len = t.audio_file.len();
+            if keep >= RECENT_TRACKS_MAX_COUNT || total_bytes + len > RECENT_TRACKS_MAX_BYTES {
+                break;
+            }
+            total_bytes += len;
+            keep += 1;
+        }
+        self.recent_tracks.truncate(keep);
+    }
+
+    fn take_recent_track(&mut self, track_id: SpotifyId) -> Option<RoonPlayerLoadedTrack> {
+        let index = self.recent_tracks.iter().position(|t| t.audio.id == track_id)?;
+        self.recent_tracks.remove(index)
+    }
+
     fn send_event(&mut self, event: PlayerEvent) {
         info!("Sending PlayerEvent {:?}", event);
         self.event_senders
@@ -433,12 +649,16 @@ impl PlayerInternal {
         let loader = PlayerTrackLoader {
             session: self.session.clone(),
             config: self.config.clone(),
+            formats: self.formats.clone(),
+            audio_cache_limiter: self.audio_cache_limiter.clone(),
         };
 
         let (result_tx, result_rx) = oneshot::channel();
 
         std::thread::spawn(move || {
+            let started = Instant::now();
             let data = futures_executor::block_on(loader.load_track(spotify_id, position_ms));
+            metrics::observe_track_load(started.elapsed(), data.is_some());
             if let Some(data) = data {
                 let _ = result_tx.send(data);
             }
@@ -454,6 +674,34 @@ impl Drop for PlayerInternal {
     }
 }
 
+fn stream_data_rate(format: FileFormat) -> usize {
+    match format {
+        FileFormat::OGG_VORBIS_96 => 12 * 1024,
+        FileFormat::OGG_VORBIS_160 => 20 * 1024,
+        FileFormat::OGG_VORBIS_320 => 40 * 1024,
+        FileFormat::MP3_256 => 32 * 1024,
+        FileFormat::MP3_320 => 40 * 1024,
+        FileFormat::MP3_160 => 20 * 1024,
+        FileFormat::MP3_96 => 12 * 1024,
+        FileFormat::MP3_160_ENC => 20 * 1024,
+        FileFormat::MP4_128_DUAL => 16 * 1024,
+        FileFormat::OTHER3 => 40 * 1024, // better some high guess than nothing
+        FileFormat::AAC_160 => 20 * 1024,
+        FileFormat::AAC_320 => 40 * 1024,
+        FileFormat::MP4_128 => 16 * 1024,
+        FileFormat::OTHER5 => 40 * 1024, // better some high guess than nothing
+    }
+}
+
+fn has_ogg_header<T: Read + Seek>(file: &mut T) -> bool {
+    let mut magic = [0u8; 4];
+    let ok = file.seek(SeekFrom::Start(SPOTIFY_OGG_HEADER_END)).is_ok() &&
+        file.read_exact(&mut magic).is_ok() &&
+        &magic == b"OggS";
+    let _ = file.seek(SeekFrom::Start(0));
+    ok
+}
+
 struct Subfile<T: Read + Seek> {
     stream: T,
     offset: u64,
@@ -495,3 +743,7 @@ impl<T: Read + Seek> Seek for Subfile<T> {
 mod handle_roon_message;
 mod handle_player_command;
 mod handle_server_message;
+mod preload_timing;
+
+pub use self::preload_timing::{PreloadTimer};
+use self::preload_timing::{DownloadProgress};
@@ -0,0 +1,257 @@
+use std::time::Instant;
+use librespot::audio::range_set::Range;
+use crate::zone::{PreloadTiming};
+use super::*;
+
+// Lead used until a download has been timed, the old fixed value
+const UNMEASURED_PRELOAD_LEAD_MS: u32 = 30000;
+const DEFAULT_MIN_PRELOAD_LEAD_MS: u32 = 10000;
+// Roon reads this much of the next track before it starts playing it
+const NEXT_TRACK_BUFFER_SECS: usize = 10;
+// Weight of the newest download in the running throughput estimate
+const THROUGHPUT_SMOOTHING: f64 = 0.3;
+// Granularity of the downloaded bytes reading
+const DOWNLOAD_PROBE_SIZE: usize = 64 * 1024;
+
+// Download of a loaded track, timed from open until the stream loader has all of it
+pub struct DownloadProgress {
+    pub opened_at:    Instant,
+    // Cached and seeked files don't download in stream mode, their timing says nothing
+    pub timed:        bool,
+    // First time the stream loader had the whole file
+    pub completed_at: Option<Instant>,
+}
+
+impl DownloadProgress {
+    pub fn new(opened_at: Instant, timed: bool) -> DownloadProgress {
+        DownloadProgress { opened_at, timed, completed_at: None }
+    }
+}
+
+// Seeks leave gaps in what was fetched, so every piece of the file is checked
+fn downloaded_bytes(controller: &StreamLoaderController) -> usize {
+    let len = controller.len();
+    (0..len)
+        .step_by(DOWNLOAD_PROBE_SIZE)
+        .map(|start| (start, DOWNLOAD_PROBE_SIZE.min(len - start)))
+        .filter(|&(start, length)| controller.range_available(Range::new(start, length)))
+        .map(|(_, length)| length)
+        .sum()
+}
+
+fn remaining_bytes(track: &RoonPlayerLoadedTrack) -> u64 {
+    if track.download.completed_at.is_some() {
+        return 0;
+    }
+    let controller = &track.stream_loader_controller;
+    controller.len().saturating_sub(downloaded_bytes(controller)) as u64
+}
+
+// Kept by the zone, so the throughput measured survives reconnects
+pub struct PreloadTimer {
+    min_lead_ms:     u32,
+    throughput:      Option<f64>, // bytes per second
+    // Track and time to its end when the last preload was suggested
+    last_suggestion: Option<(SpotifyId, u32)>,
+}
+
+impl PreloadTimer {
+    pub fn new(min_lead_ms: Option<u32>) -> PreloadTimer {
+        PreloadTimer {
+            min_lead_ms:     min_lead_ms.unwrap_or(DEFAULT_MIN_PRELOAD_LEAD_MS),
+            throughput:      None,
+            last_suggestion: None,
+        }
+    }
+
+    // Called for the current and the preloaded track, a preload finishes downloading
+    // long before it plays and must not be timed by when it started playing
+    fn sample(&mut self, track: &mut RoonPlayerLoadedTrack) {
+        if track.download.completed_at.is_none() && track.stream_loader_controller.range_to_end_available() {
+            self.completed(&mut track.download, track.stream_loader_controller.len(), Instant::now());
+        }
+    }
+
+    fn completed(&mut self, download: &mut DownloadProgress, len: usize, completed_at: Instant) {
+        download.completed_at = Some(completed_at);
+        let secs = completed_at.duration_since(download.opened_at).as_secs_f64();
+        if !download.timed || secs <= 0.0 {
+            return;
+        }
+        let rate = len as f64 / secs;
+        self.throughput = Some(match self.throughput {
+            Some(throughput) => throughput + THROUGHPUT_SMOOTHING * (rate - throughput),
+            None             => rate
+        });
+    }
+
+    // The rest of this file and the start of the next one have to arrive before the end
+    fn lead_ms(&self, remaining_bytes: u64, bytes_per_second: usize, ping_ms: u32) -> u32 {
+        match self.throughput {
+            Some(throughput) => {
+                let bytes = remaining_bytes + (bytes_per_second * NEXT_TRACK_BUFFER_SECS) as u64;
+                (bytes as f64 / throughput * 1000.0) as u32 + ping_ms
+            },
+            None => UNMEASURED_PRELOAD_LEAD_MS
+        }.max(self.min_lead_ms)
+    }
+
+    pub fn timing(&self, remaining_bytes: u64, bytes_per_second: usize, ping_ms: u32, track_id: SpotifyId) -> PreloadTiming {
+        PreloadTiming {
+            throughput_bytes_per_sec: self.throughput.map(|t| t as u64),
+            remaining_bytes,
+            min_lead_ms:              self.min_lead_ms,
+            lead_ms:                  self.lead_ms(remaining_bytes, bytes_per_second, ping_ms),
+            suggested_at_ms:          self.last_suggestion
+                .filter(|(id, _)| *id == track_id)
+                .map(|(_, time_to_end)| time_to_end),
+        }
+    }
+}
+
+impl PlayerInternal {
+    // If it is time to load the next track, let spirc know and it will call preload on
+    // this player with the next track id
+    pub fn suggest_preload(&mut self) {
+        if let PlayerState::Playing {
+            track_id,
+            play_request_id,
+            duration_ms,
+            position_ms,
+            ref mut track,
+            ref mut suggested_to_preload_next_track,
+            ..
+        }
+        | PlayerState::Paused {
+            track_id,
+            play_request_id,
+            duration_ms,
+            position_ms,
+            ref mut track,
+            ref mut suggested_to_preload_next_track,
+            ..
+        } = self.state
+        {
+            let mut preload_timer = self.preload_timer.lock().unwrap();
+            preload_timer.sample(track);
+            if let PlayerPreload::Ready { ref mut loaded_track, .. } = self.preload {
+                preload_timer.sample(loaded_track);
+            }
+            if *suggested_to_preload_next_track {
+                return;
+            }
+            let time_to_end = duration_ms.saturating_sub(position_ms);
+            let bytes_per_second = stream_data_rate(track.format);
+            let ping_ms = track.stream_loader_controller.ping_time_ms() as u32;
+            // Finding what is left to download probes the whole file, the lead for
+            // all of it says whether it can matter yet
+            let file_size = track.stream_loader_controller.len() as u64;
+            if time_to_end >= preload_timer.lead_ms(file_size, bytes_per_second, ping_ms) {
+                return;
+            }
+            let timing = preload_timer.timing(remaining_bytes(track), bytes_per_second, ping_ms, track_id);
+            if time_to_end < timing.lead_ms {
+                info!("Preloading after <{}> with {}ms left, lead {}ms (throughput {:?} B/s, {} bytes left)",
+                    track.audio.name, time_to_end, timing.lead_ms,
+                    timing.throughput_bytes_per_sec, timing.remaining_bytes);
+                *suggested_to_preload_next_track = true;
+                preload_timer.last_suggestion = Some((track_id, time_to_end));
+                drop(preload_timer);
+                self.send_event(PlayerEvent::TimeToPreloadNextTrack {
+                    track_id,
+                    play_request_id,
+                });
+            }
+        }
+    }
+
+    pub fn preload_timing(&self) -> Option<PreloadTiming> {
+        match &self.state {
+            PlayerState::Playing { track_id, track, .. } |
+            PlayerState::Paused  { track_id, track, .. } => {
+                let ping_ms = track.stream_loader_controller.ping_time_ms() as u32;
+                let timing = self.preload_timer.lock().unwrap()
+                    .timing(remaining_bytes(track), stream_data_rate(track.format), ping_ms, *track_id);
+                Some(timing)
+            },
+            _ => None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn track_id() -> SpotifyId {
+        SpotifyId::from_base62("4uLU6hMCjMI75M1A2tKUQC").unwrap()
+    }
+
+    // A download of `len` bytes that took `secs` to complete
+    fn download(timer: &mut PreloadTimer, len: usize, secs: u64, timed: bool) -> DownloadProgress {
+        let opened_at = Instant::now();
+        let mut download = DownloadProgress::new(opened_at, timed);
+        timer.completed(&mut download, len, opened_at + Duration::from_secs(secs));
+        download
+    }
+
+    #[test]
+    fn unmeasured_uses_the_fixed_lead() {
+        let timing = PreloadTimer::new(None).timing(1_000_000, 20_000, 50, track_id());
+        assert_eq!(timing.lead_ms, UNMEASURED_PRELOAD_LEAD_MS);
+        assert_eq!(timing.throughput_bytes_per_sec, None);
+        assert_eq!(timing.min_lead_ms, DEFAULT_MIN_PRELOAD_LEAD_MS);
+        assert_eq!(timing.suggested_at_ms, None);
+
+        assert_eq!(PreloadTimer::new(Some(45000)).timing(0, 20_000, 0, track_id()).lead_ms, 45000);
+    }
+
+    #[test]
+    fn lead_covers_the_rest_of_the_file_and_the_next_track_buffer() {
+        let mut timer = PreloadTimer::new(Some(0));
+        download(&mut timer, 1_000_000, 10, true);
+        // 300000 bytes left and 10s of the next track at 20000 B/s, 5s at 100000 B/s
+        let timing = timer.timing(300_000, 20_000, 50, track_id());
+        assert_eq!(timing.throughput_bytes_per_sec, Some(100_000));
+        assert_eq!(timing.remaining_bytes, 300_000);
+        assert_eq!(timing.lead_ms, 5050);
+    }
+
+    #[test]
+    fn lead_is_clamped_to_the_minimum() {
+        let mut timer = PreloadTimer::new(Some(10000));
+        download(&mut timer, 10_000_000, 1, true);
+        assert_eq!(timer.timing(0, 20_000, 0, track_id()).lead_ms, 10000);
+    }
+
+    #[test]
+    fn throughput_is_smoothed() {
+        let mut timer = PreloadTimer::new(None);
+        download(&mut timer, 1_000_000, 10, true);
+        download(&mut timer, 1_000_000, 5, true);
+        // 100000 B/s moved 30% of the way to 200000 B/s
+        let throughput = timer.timing(0, 0, 0, track_id()).throughput_bytes_per_sec.unwrap();
+        assert!((129_999..=130_000).contains(&throughput), "{}", throughput);
+    }
+
+    #[test]
+    fn cached_and_seeked_downloads_are_not_timed() {
+        let mut timer = PreloadTimer::new(None);
+        let untimed = download(&mut timer, 1_000_000, 1, false);
+        assert!(untimed.completed_at.is_some());
+        assert_eq!(timer.timing(0, 0, 0, track_id()).throughput_bytes_per_sec, None);
+
+        download(&mut timer, 1_000_000, 0, true);
+        assert_eq!(timer.timing(0, 0, 0, track_id()).throughput_bytes_per_sec, None);
+    }
+
+    #[test]
+    fn suggestion_is_only_reported_for_its_track() {
+        let mut timer = PreloadTimer::new(None);
+        timer.last_suggestion = Some((track_id(), 12000));
+        assert_eq!(timer.timing(0, 0, 0, track_id()).suggested_at_ms, Some(12000));
+        let other = SpotifyId::from_base62("6rqhFgbbKwnb9MLmUQDhG6").unwrap();
+        assert_eq!(timer.timing(0, 0, 0, other).suggested_at_ms, None);
+    }
+}
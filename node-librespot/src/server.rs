@@ -3,99 +3,245 @@ use actix_web::{
         SizedStream
     },
     http::{
-        header::{self, HeaderValue},
+        header::{self, HeaderValue, HttpDate},
         StatusCode
     },
-    get, web, App, HttpServer, Responder, HttpRequest, HttpResponse};
-use http_range::HttpRange;
+    get, route, web, App, HttpServer, Responder, HttpRequest, HttpResponse, ResponseError};
+use actix_web::http::Method;
+use actix_web::web::Bytes;
+use http_range::{HttpRange, HttpRangeParseError};
+use futures_util::{future, stream, StreamExt};
+use rand::Rng;
 use std::sync::Mutex;
-use std::sync::Arc;
-use tokio::sync::mpsc::{UnboundedSender};
-use std::sync::mpsc::channel;
+use tokio::sync::mpsc::{self, UnboundedSender};
+use tokio::sync::oneshot;
 use std::sync::mpsc::Sender;
 use tokio;
+use std::fmt;
+use std::future::Future;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use std::task::{Context, Poll};
 use core::pin::Pin;
 use futures_core::Stream;
 
 use actix_web::dev::ServerHandle;
+use librespot::metadata::FileFormat;
+use librespot::core::spotify_id::FileId;
+use serde::Deserialize;
+use crate::transcode::{can_transcode, TrackReader, TranscodeFormat, Transcoder};
+use crate::stream_token::{StreamSigner};
+use crate::zone::{ZoneStatus};
+use crate::metrics;
+use crate::allow_list::ClientAllowList;
 use actix_http_test::unused_addr;
 
 
 struct ServerInternal {
-    devices_tx: UnboundedSender<ServerMessage>
+    devices_tx:    UnboundedSender<ServerMessage>,
+    load_timeout:  Duration, // How long a request waits on a loading track
+    stream_signer: StreamSigner,
+    status_clients: ClientAllowList, // Who may read zone status and metrics
+    last_modified: SystemTime // When the server started, in whole seconds
 }
 
+const RETRY_AFTER_SECS: &str = "2";
+// Devices waits up to 2s per zone, leave it room to answer
+const STATUS_TIMEOUT: Duration = Duration::from_secs(3);
 
+#[derive(Debug)]
+pub enum StreamError {
+    Forbidden,         // Missing, expired or forged stream token
+    NotAllowed,        // Status or metrics asked for by a client not on the allow-list
+    UnknownZone,       // No zone with that id
+    NotCurrent,        // Zone exists but the track isn't loaded, preloaded or recent
+    Loading,           // Still loading after the load timeout
+    FetchFailed,       // Spotify data could not be read or decoded
+    UnsupportedFormat, // Can't transcode from this format
+    Unavailable,       // Devices thread or player went away
+}
+
+impl fmt::Display for StreamError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let msg = match self {
+            StreamError::Forbidden         => "Invalid stream token",
+            StreamError::NotAllowed        => "Client is not allowed to read status",
+            StreamError::UnknownZone       => "Unknown zone",
+            StreamError::NotCurrent        => "Track is not loaded in this zone",
+            StreamError::Loading           => "Track is still loading",
+            StreamError::FetchFailed       => "Failed to fetch track from Spotify",
+            StreamError::UnsupportedFormat => "Track format can not be transcoded",
+            StreamError::Unavailable       => "Player unavailable",
+        };
+        write!(f, "{}", msg)
+    }
+}
+
+impl StreamError {
+    fn label(&self) -> &'static str {
+        match self {
+            StreamError::Forbidden         => "forbidden",
+            StreamError::NotAllowed        => "not_allowed",
+            StreamError::UnknownZone       => "unknown_zone",
+            StreamError::NotCurrent        => "not_current",
+            StreamError::Loading           => "loading",
+            StreamError::FetchFailed       => "fetch_failed",
+            StreamError::UnsupportedFormat => "unsupported_format",
+            StreamError::Unavailable       => "unavailable",
+        }
+    }
+}
+
+impl ResponseError for StreamError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            StreamError::Forbidden         => StatusCode::FORBIDDEN,
+            StreamError::NotAllowed        => StatusCode::FORBIDDEN,
+            StreamError::UnknownZone       => StatusCode::NOT_FOUND,
+            StreamError::NotCurrent        => StatusCode::CONFLICT,
+            StreamError::Loading           => StatusCode::SERVICE_UNAVAILABLE,
+            StreamError::FetchFailed       => StatusCode::BAD_GATEWAY,
+            StreamError::UnsupportedFormat => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            StreamError::Unavailable       => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        metrics::record_stream_error(self.label());
+        let mut res = HttpResponse::build(self.status_code());
+        if let StreamError::Loading = self {
+            res.insert_header((header::RETRY_AFTER, RETRY_AFTER_SECS));
+        }
+        res.body(self.to_string())
+    }
+}
+
+impl From<ServerReply> for StreamError {
+    fn from(reply: ServerReply) -> StreamError {
+        match reply {
+            ServerReply::UnknownZone => StreamError::UnknownZone,
+            ServerReply::NotCurrent  => StreamError::NotCurrent,
+            ServerReply::ReadError   => StreamError::FetchFailed,
+            _ => {
+                error!("Unexpected reply from player {:?}", reply);
+                StreamError::Unavailable
+            }
+        }
+    }
+}
+
+
+const CHUNK_SIZE: usize = 32 * 1024;
+const TRANSCODE_BUFFERED_CHUNKS: usize = 8;
+
+// Streams the bytes [readpos, end) of a track. Each chunk is requested from the zone's
+// player and the reply is awaited without blocking the actix worker.
 struct SpotifyStreamer {
     track_id:   String,
     zone_id:    String,
     readpos:    usize,
-    file_size:  usize,
+    end:        usize,
     devices_tx: UnboundedSender<ServerMessage>,
+    pending:    Option<oneshot::Receiver<ServerReply>>,
+    requested:  Instant, // When the pending chunk was asked for
+}
+
+impl SpotifyStreamer {
+    fn new(track_id: String, zone_id: String, start: usize, end: usize, devices_tx: UnboundedSender<ServerMessage>) -> SpotifyStreamer {
+        SpotifyStreamer {
+            track_id,
+            zone_id,
+            readpos: start,
+            end,
+            devices_tx,
+            pending: None,
+            requested: Instant::now()
+        }
+    }
+}
+
+// Errors after the headers went out end the body, error_response never sees them
+fn stream_error(err: StreamError) -> actix_web::Error {
+    metrics::record_stream_error(err.label());
+    err.into()
 }
 
 impl Stream for SpotifyStreamer {
     type Item = Result<actix_web::web::Bytes, actix_web::Error>;
 
-    fn poll_next(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        if self.readpos >= self.file_size {
-            return Poll::Ready(None); // Stream ended
-        }
-
-        let mut _buff = vec![0u8; 32*1024 as usize];
-        let buff      = Arc::new(Mutex::new(_buff));
-        let (responder, receiver) = channel::<ServerReply>();
-
-        // Ask devices to give you chunk of data
-        match self.devices_tx.send(ServerMessage::TrackRead {
-            zone_id:   self.zone_id.clone(),
-            track_id:  self.track_id.clone(),
-            start:     self.readpos, 
-            end:       self.readpos + 32768, // XXX
-            out:       buff.clone(),
-            responder
-        }) {
-            Err(e) => {
-                error!("Error requesting chunk of data from devices thread {}", e);
-                return Poll::Ready(Some(Err(actix_web::error::ErrorInternalServerError("Devices thread shut down while streaming"))));
-            },
-            _ => ()
-        };
-        match receiver.recv()
-        {
-            Ok(msg) => match msg {
-                ServerReply::TrackRead {
-                    read_len,
-                    ..
-                } => {
-                    self.readpos += read_len;
-                    let unwrapped = buff.lock().unwrap();
-                    Poll::Ready(Some(Ok(unwrapped[..read_len].to_vec().into())))
-                },
-                    _ => {
-                        Poll::Ready(Some(Err(actix_web::error::ErrorInternalServerError("Received invalid message back from oneshot"))))
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            // Waiting on a chunk, the player wakes us once it replies
+            if let Some(receiver) = self.pending.as_mut() {
+                let reply = match Pin::new(receiver).poll(cx) {
+                    Poll::Pending    => return Poll::Pending,
+                    Poll::Ready(msg) => msg
+                };
+                self.pending = None;
+                metrics::observe_track_read(self.requested.elapsed());
+                return match reply {
+                    Ok(ServerReply::TrackRead { data, .. }) => {
+                        if data.is_empty() {
+                            error!("Track {} ended before requested range", self.track_id);
+                            return Poll::Ready(Some(Err(stream_error(StreamError::FetchFailed))));
+                        }
+                        self.readpos += data.len();
+                        metrics::record_bytes_served(&self.zone_id, data.len());
+                        Poll::Ready(Some(Ok(data.into())))
+                    },
+                    Ok(reply) => {
+                        Poll::Ready(Some(Err(stream_error(StreamError::from(reply)))))
+                    },
+                    Err(_err) => {
+                        Poll::Ready(Some(Err(stream_error(StreamError::Unavailable))))
                     }
-            },
-            Err(_err) => {
-                Poll::Ready(Some(Err(actix_web::error::ErrorInternalServerError("Error"))))
+                };
+            }
+
+            if self.readpos >= self.end {
+                return Poll::Ready(None); // Stream ended
             }
+
+            let chunk_len = CHUNK_SIZE.min(self.end - self.readpos);
+            let (responder, receiver) = oneshot::channel::<ServerReply>();
+
+            // Ask devices to give you chunk of data
+            match self.devices_tx.send(ServerMessage::TrackRead {
+                zone_id:   self.zone_id.clone(),
+                track_id:  self.track_id.clone(),
+                start:     self.readpos, 
+                end:       self.readpos + chunk_len,
+                responder
+            }) {
+                Err(e) => {
+                    error!("Error requesting chunk of data from devices thread {}", e);
+                    return Poll::Ready(Some(Err(stream_error(StreamError::Unavailable))));
+                },
+                _ => ()
+            };
+            self.pending   = Some(receiver);
+            self.requested = Instant::now();
         }
     }
 }
 
+#[derive(Debug)]
 pub enum ServerReply {
-    Busy,
-    NotFound,
+    UnknownZone,
+    NotCurrent,
+    ReadError,
     TrackInfo {
         file_size: usize,
+        format:    FileFormat,
+        file_id:   FileId,
         track_id:  String,
     },
     TrackRead {
-        read_len: usize,
+        data:      Vec<u8>,
         file_size: usize,
         track_id:  String,
-    }
+    },
+    Zones(Vec<ZoneStatus>),
+    ZoneStatus(ZoneStatus)
 }
 
 
@@ -104,15 +250,26 @@ pub enum ServerMessage {
     TrackInfo {
         zone_id: String,
         track_id: String,
-        responder: Sender<ServerReply>
+        responder: oneshot::Sender<ServerReply>
     },
     TrackRead {
         zone_id: String,
         track_id: String,
         start:   usize,
         end:     usize,
-        out:     Arc<Mutex<Vec<u8>>>,
-        responder: Sender<ServerReply>
+        responder: oneshot::Sender<ServerReply>
+    },
+    Zones {
+        responder: oneshot::Sender<ServerReply>
+    },
+    ZoneStatus {
+        zone_id: String,
+        responder: oneshot::Sender<ServerReply>
+    },
+    // Zone to player, the player adds its own state and replies
+    PlayerStatus {
+        zone: ZoneStatus,
+        responder: oneshot::Sender<ServerReply>
     }
 }
 
@@ -122,68 +279,333 @@ async fn greet(name: web::Path<String>) -> impl Responder {
         format!("Hello {name}!")
 }
 
-use std::time::{SystemTime};
+#[get("/metrics")]
+async fn metrics_endpoint(req: HttpRequest, data: web::Data<Mutex<ServerInternal>>) -> Result<HttpResponse, StreamError> {
+    require_status_client(&req, &data)?;
+    Ok(HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(metrics::render()))
+}
+
+async fn status_request(
+    data: &web::Data<Mutex<ServerInternal>>,
+    msg:  ServerMessage,
+    receiver: oneshot::Receiver<ServerReply>
+) -> Result<ServerReply, StreamError> {
+    let devices_tx = data.lock().unwrap().devices_tx.clone();
+    if devices_tx.send(msg).is_err() {
+        return Err(StreamError::Unavailable);
+    }
+    match tokio::time::timeout(STATUS_TIMEOUT, receiver).await {
+        Ok(Ok(reply)) => Ok(reply),
+        _ => Err(StreamError::Unavailable)
+    }
+}
 
-#[get("/stream/{zone_id}/{req_track_id}")]
-async fn stream(
-    req:  HttpRequest, 
-    path: web::Path<(String,String)>, 
+// Zone status names the user's Spotify accounts and tracks, and the server listens on
+// every interface for Roon, so status and metrics are only answered to allowed clients
+fn require_status_client(req: &HttpRequest, data: &web::Data<Mutex<ServerInternal>>) -> Result<(), StreamError> {
+    match req.peer_addr() {
+        Some(addr) if data.lock().unwrap().status_clients.allows(addr.ip()) => Ok(()),
+        _ => Err(StreamError::NotAllowed)
+    }
+}
+
+#[get("/zones")]
+async fn zones(req: HttpRequest, data: web::Data<Mutex<ServerInternal>>) -> Result<HttpResponse, StreamError> {
+    require_status_client(&req, &data)?;
+    let (responder, receiver) = oneshot::channel::<ServerReply>();
+    match status_request(&data, ServerMessage::Zones { responder }, receiver).await? {
+        ServerReply::Zones(statuses) => Ok(HttpResponse::Ok().json(statuses)),
+        reply => Err(reply.into())
+    }
+}
+
+#[get("/zones/{zone_id}")]
+async fn zone(
+    req:  HttpRequest,
+    path: web::Path<String>,
     data: web::Data<Mutex<ServerInternal>>
-) -> HttpResponse {
-    let (zone_id,req_track_id) = path.into_inner();
-    let _headers = req.headers();
-        let _start = SystemTime::now();
-    info!("HTTP REQ ZONE: {}", zone_id);
-    info!("     REQ TRACK: {}", req_track_id);
-    // XXX Roon doesnt use the range-end portion 
-    // of the range request header, so always assuming start -> end of file
-    // for now.
-    let mut offset      = 0    as usize;
-    if let Some(ranges) = req.headers().get(header::RANGE) {
-        if let Ok(ranges_header) = ranges.to_str() {
-            if let Ok(ranges) = HttpRange::parse(ranges_header, 10000000000) {
-                let length = ranges[0].length as usize;
-                offset     = ranges[0].start as usize;
-                debug!("RANGE: {} - {}", offset, length);
-            }
-        }
+) -> Result<HttpResponse, StreamError> {
+    require_status_client(&req, &data)?;
+    let (responder, receiver) = oneshot::channel::<ServerReply>();
+    let msg = ServerMessage::ZoneStatus { zone_id: path.into_inner(), responder };
+    match status_request(&data, msg, receiver).await? {
+        ServerReply::ZoneStatus(status) => Ok(HttpResponse::Ok().json(status)),
+        reply => Err(reply.into())
     }
+}
 
-    let state = data.lock().unwrap();
-    let (responder, receiver) = channel::<ServerReply>();
-    match state.devices_tx.send(ServerMessage::TrackInfo {
-        zone_id:   zone_id.clone(),
-        track_id:  req_track_id.clone(),
+fn content_type(format: FileFormat) -> &'static str {
+    match format {
+        FileFormat::OGG_VORBIS_96 |
+        FileFormat::OGG_VORBIS_160 |
+        FileFormat::OGG_VORBIS_320 => "audio/ogg",
+        FileFormat::MP3_96 |
+        FileFormat::MP3_160 |
+        FileFormat::MP3_160_ENC |
+        FileFormat::MP3_256 |
+        FileFormat::MP3_320 => "audio/mpeg",
+        // Spotify's AAC files are MP4 containers, not ADTS streams
+        FileFormat::AAC_160 |
+        FileFormat::AAC_320 |
+        FileFormat::MP4_128 |
+        FileFormat::MP4_128_DUAL => "audio/mp4",
+        FileFormat::OTHER3 |
+        FileFormat::OTHER5 => "application/octet-stream"
+    }
+}
+
+fn etag(file_id: &FileId, suffix: &str) -> String {
+    format!("\"{}{}\"", hex::encode(file_id.0), suffix)
+}
+
+// Audio behind a file id never changes, but the server can only vouch for it
+// since it started, so that is the Last-Modified date of every file
+fn last_modified_now() -> SystemTime {
+    let since_epoch = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    UNIX_EPOCH + Duration::from_secs(since_epoch.as_secs())
+}
+
+fn http_date(value: &str) -> Option<SystemTime> {
+    value.parse::<HttpDate>().ok().map(SystemTime::from)
+}
+
+// If-None-Match wins over If-Modified-Since, as in RFC 7232
+fn is_not_modified(req: &HttpRequest, etag: &str, last_modified: SystemTime) -> bool {
+    if let Some(if_none_match) = req.headers().get(header::IF_NONE_MATCH).and_then(|h| h.to_str().ok()) {
+        return if_none_match
+            .split(',')
+            .map(|tag| tag.trim().trim_start_matches("W/"))
+            .any(|tag| tag == "*" || tag == etag);
+    }
+    match req.headers().get(header::IF_MODIFIED_SINCE).and_then(|h| h.to_str().ok()).and_then(http_date) {
+        Some(since) => last_modified <= since,
+        None        => false
+    }
+}
+
+// If-Range needs an exact match on the etag or the date, RFC 7233
+fn if_range_matches(req: &HttpRequest, etag: &str, last_modified: SystemTime) -> bool {
+    match req.headers().get(header::IF_RANGE).and_then(|h| h.to_str().ok()) {
+        Some(if_range) => if_range == etag || http_date(if_range) == Some(last_modified),
+        None           => true
+    }
+}
+
+fn not_modified(etag: String, last_modified: SystemTime) -> HttpResponse {
+    HttpResponse::build(StatusCode::NOT_MODIFIED)
+        .insert_header((header::ETAG, etag))
+        .insert_header((header::LAST_MODIFIED, HttpDate::from(last_modified).to_string()))
+        .finish()
+}
+
+fn empty_body() -> impl Stream<Item = Result<Bytes, actix_web::Error>> {
+    stream::empty()
+}
+
+// Asks the zone's player for the size, format and file id of a track, waiting
+// up to `load_timeout` for it to finish loading
+async fn track_info(
+    devices_tx:   &UnboundedSender<ServerMessage>,
+    zone_id:      &str,
+    track_id:     &str,
+    load_timeout: Duration
+) -> Result<(usize, FileFormat, FileId), StreamError> {
+    let (responder, receiver) = oneshot::channel::<ServerReply>();
+    if let Err(e) = devices_tx.send(ServerMessage::TrackInfo {
+        zone_id:   zone_id.to_string(),
+        track_id:  track_id.to_string(),
         responder
     }) {
-        Err(e) => {
-            error!("Error sending track info request to devices thread {}", e);
-            return HttpResponse::build(StatusCode::NOT_FOUND).finish();
+        error!("Error sending track info request to devices thread {}", e);
+        return Err(StreamError::Unavailable);
+    }
+    info!("TRACK INFO REQ");
+    match tokio::time::timeout(load_timeout, receiver).await {
+        Ok(Ok(ServerReply::TrackInfo { file_size, format, file_id, .. })) => Ok((file_size, format, file_id)),
+        Ok(Ok(reply)) => Err(reply.into()),
+        Ok(Err(_err)) => Err(StreamError::Unavailable),
+        Err(_elapsed) => {
+            warn!("Timed out waiting for {} to load", track_id);
+            Err(StreamError::Loading)
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct StreamQuery {
+    token: Option<String>
+}
+
+#[derive(Deserialize, Debug)]
+struct TranscodeQuery {
+    token:    Option<String>,
+    start_ms: Option<u32>
+}
+
+// Checks the token from the stream url handed out with Play/Preload, then
+// returns what the handler needs from the server state
+fn authorize(
+    data:     &web::Data<Mutex<ServerInternal>>,
+    zone_id:  &str,
+    track_id: &str,
+    token:    Option<&str>
+) -> Result<(UnboundedSender<ServerMessage>, Duration), StreamError> {
+    let state = data.lock().unwrap();
+    match token {
+        Some(token) if state.stream_signer.verify(zone_id, track_id, token) => {
+            Ok((state.devices_tx.clone(), state.load_timeout))
         },
-        _ => ()
+        _ => {
+            warn!("Rejected stream request for {} in zone {}", track_id, zone_id);
+            Err(StreamError::Forbidden)
+        }
+    }
+}
+
+// Decoded Vorbis re-encoded as WAV or FLAC. The output length isn't known ahead
+// of time so the body is sent chunked, and range requests are not supported.
+#[route("/stream/{zone_id}/{req_track_id:[^/.]+}.{ext:(flac|wav)}", method = "GET", method = "HEAD")]
+async fn transcode_stream(
+    req:   HttpRequest,
+    path:  web::Path<(String,String,String)>,
+    query: web::Query<TranscodeQuery>,
+    data:  web::Data<Mutex<ServerInternal>>
+) -> Result<HttpResponse, StreamError> {
+    let (zone_id, req_track_id, ext) = path.into_inner();
+    let transcode_format = match TranscodeFormat::from_extension(&ext) {
+        Some(transcode_format) => transcode_format,
+        None => return Err(StreamError::UnsupportedFormat)
     };
-    info!("TRACK INFO REQ");
-    let file_size;
-    match receiver.recv()
-        {
-            Ok(msg) => match msg
-            {
-                ServerReply::TrackInfo
-                {
-                    file_size: track_file_size,
-                    ..
-                } => {
-                    file_size = track_file_size
+    info!("HTTP TRANSCODE REQ ZONE: {} TRACK: {} AS: {}", zone_id, req_track_id, ext);
+
+    let (devices_tx, load_timeout) = authorize(&data, &zone_id, &req_track_id, query.token.as_deref())?;
+    let (file_size, format, file_id) = track_info(&devices_tx, &zone_id, &req_track_id, load_timeout).await?;
+    if !can_transcode(format) {
+        warn!("Can not transcode {} from {:?}", req_track_id, format);
+        return Err(StreamError::UnsupportedFormat);
+    }
+    // Seeks give different bytes, so they're part of the tag
+    let etag = etag(&file_id, &match query.start_ms {
+        Some(start_ms) => format!(".{}.{}", start_ms, ext),
+        None           => format!(".{}", ext)
+    });
+    let last_modified = data.lock().unwrap().last_modified;
+    if is_not_modified(&req, &etag, last_modified) {
+        return Ok(not_modified(etag, last_modified));
+    }
+
+    let mut res = HttpResponse::build(StatusCode::OK);
+    res.insert_header((
+            header::CONTENT_TYPE,
+            HeaderValue::from_static(transcode_format.content_type()),
+            ));
+    res.insert_header((
+            header::ACCEPT_RANGES,
+            HeaderValue::from_static("none"),
+            ));
+    res.insert_header((header::ETAG, etag));
+    res.insert_header((header::LAST_MODIFIED, HttpDate::from(last_modified).to_string()));
+    if req.method() == Method::HEAD {
+        return Ok(res.finish());
+    }
+
+    // Decoding blocks on every read, so it runs on the blocking pool and hands
+    // encoded chunks back over a bounded channel
+    let (chunk_tx, chunk_rx) = mpsc::channel::<std::io::Result<Bytes>>(TRANSCODE_BUFFERED_CHUNKS);
+    let (ready_tx, ready_rx) = oneshot::channel::<std::io::Result<()>>();
+    let reader = TrackReader::new(zone_id.clone(), req_track_id.clone(), file_size, devices_tx);
+    let start_ms = query.start_ms;
+    tokio::task::spawn_blocking(move || {
+        let transcoder = match Transcoder::open(reader, transcode_format, start_ms) {
+            Ok(transcoder) => {
+                let _ = ready_tx.send(Ok(()));
+                transcoder
+            },
+            Err(e) => {
+                let _ = ready_tx.send(Err(e));
+                return;
+            }
+        };
+        if let Err(e) = transcoder.run(&chunk_tx) {
+            error!("Error transcoding track: {}", e);
+            let _ = chunk_tx.blocking_send(Err(e));
+        }
+    });
+
+    match ready_rx.await {
+        Ok(Ok(())) => (),
+        Ok(Err(e)) => {
+            error!("Could not open {} for transcoding: {}", req_track_id, e);
+            return Err(StreamError::FetchFailed);
+        },
+        Err(_err) => return Err(StreamError::Unavailable)
+    }
+
+    let body = stream::unfold((chunk_rx, zone_id), |(mut chunk_rx, zone_id)| async move {
+        let chunk = chunk_rx.recv().await?;
+        if let Ok(data) = &chunk {
+            metrics::record_bytes_served(&zone_id, data.len());
+        }
+        Some((chunk, (chunk_rx, zone_id)))
+    });
+    Ok(res.streaming(body))
+}
+
+#[route("/stream/{zone_id}/{req_track_id}", method = "GET", method = "HEAD")]
+async fn stream(
+    req:   HttpRequest,
+    path:  web::Path<(String,String)>,
+    query: web::Query<StreamQuery>,
+    data:  web::Data<Mutex<ServerInternal>>
+) -> Result<HttpResponse, StreamError> {
+    let (zone_id,req_track_id) = path.into_inner();
+    info!("HTTP REQ ZONE: {}", zone_id);
+    info!("     REQ TRACK: {}", req_track_id);
+
+    let (devices_tx, load_timeout) = authorize(&data, &zone_id, &req_track_id, query.token.as_deref())?;
+    let (file_size, format, file_id) = track_info(&devices_tx, &zone_id, &req_track_id, load_timeout).await?;
+    info!("TRACK INFO REQ DONE");
+
+    let etag = etag(&file_id, "");
+    let last_modified = data.lock().unwrap().last_modified;
+    if is_not_modified(&req, &etag, last_modified) {
+        return Ok(not_modified(etag, last_modified));
+    }
+    // A stale If-Range means the client's partial copy is useless, send it all
+    let if_range_matches = if_range_matches(&req, &etag, last_modified);
+
+    // A malformed range header is ignored and the whole file is served
+    let mut ranges = vec![];
+    if let Some(range_header) = req.headers().get(header::RANGE).filter(|_| if_range_matches) {
+        if let Ok(range_header) = range_header.to_str() {
+            match HttpRange::parse(range_header, file_size as u64) {
+                Ok(parsed) => ranges = parsed,
+                Err(HttpRangeParseError::NoOverlap) => {
+                    return Ok(HttpResponse::build(StatusCode::RANGE_NOT_SATISFIABLE)
+                        .insert_header((header::CONTENT_RANGE, format!("bytes */{}", file_size)))
+                        .finish());
                 },
-                _ => {
-                    return HttpResponse::build(StatusCode::NOT_FOUND).finish(); 
+                Err(HttpRangeParseError::InvalidRange) => {
+                    warn!("Ignoring invalid range header {}", range_header);
                 }
             }
-            Err(_err) => {
-                return HttpResponse::build(StatusCode::NOT_FOUND).finish(); 
-            }
         }
-    info!("TRACK INFO REQ DONE");
+    }
+    for range in ranges.iter() {
+        debug!("RANGE: {} - {}", range.start, range.length);
+    }
+
+    let is_head      = req.method() == Method::HEAD;
+    let content_type = content_type(format);
+    let streamer = |start: usize, end: usize| SpotifyStreamer::new(
+        req_track_id.clone(),
+        zone_id.clone(),
+        start,
+        end,
+        devices_tx.clone()
+    );
 
     let mut res = HttpResponse::build(StatusCode::OK);
     res.insert_header((
@@ -191,66 +613,432 @@ async fn stream(
             HeaderValue::from_static("identity"),
             ));
 
-    res.insert_header((
-            header::CONTENT_TYPE,
-            HeaderValue::from_static("audio/ogg"),
-            ));
-
     res.insert_header((
             header::ACCEPT_RANGES,
             HeaderValue::from_static("bytes"),
             ));
+    res.insert_header((header::ETAG, etag));
+    res.insert_header((header::LAST_MODIFIED, HttpDate::from(last_modified).to_string()));
 
-    res.insert_header((
-            header::CONTENT_RANGE,
-            format!("bytes {}-{}/{}", offset, file_size - 1, file_size)));
-
-    // 206 if not whole file
-    if offset != 0 {
-        res.status(StatusCode::PARTIAL_CONTENT);
-    }
-
-    return res
-        .body(
-        SizedStream::new((file_size - offset) as u64,
-            SpotifyStreamer {
-                file_size,
-                track_id:   req_track_id.clone(),
-                zone_id:    zone_id.clone(),
-                readpos:    offset,
-                devices_tx: state.devices_tx.clone()
+    Ok(match ranges.len() {
+        // Whole file
+        0 => {
+            res.insert_header((
+                    header::CONTENT_TYPE,
+                    HeaderValue::from_static(content_type),
+                    ));
+            if is_head {
+                return Ok(res.body(SizedStream::new(file_size as u64, empty_body())));
             }
-        )
-    );
+            res.body(SizedStream::new(file_size as u64, streamer(0, file_size)))
+        },
+        // Single range
+        1 => {
+            let start = ranges[0].start as usize;
+            let end   = start + ranges[0].length as usize;
+            res.status(StatusCode::PARTIAL_CONTENT);
+            res.insert_header((
+                    header::CONTENT_TYPE,
+                    HeaderValue::from_static(content_type),
+                    ));
+            res.insert_header((
+                    header::CONTENT_RANGE,
+                    format!("bytes {}-{}/{}", start, end - 1, file_size)));
+            if is_head {
+                return Ok(res.body(SizedStream::new((end - start) as u64, empty_body())));
+            }
+            res.body(SizedStream::new((end - start) as u64, streamer(start, end)))
+        },
+        // Multiple ranges, each part gets its own headers
+        _ => {
+            let boundary: String = hex::encode(rand::thread_rng().gen::<[u8; 16]>());
+            let parts: Vec<(String, usize, usize)> = ranges.iter().map(|range| {
+                let start = range.start as usize;
+                let end   = start + range.length as usize;
+                let part_header = format!(
+                    "\r\n--{}\r\nContent-Type: {}\r\nContent-Range: bytes {}-{}/{}\r\n\r\n",
+                    boundary, content_type, start, end - 1, file_size
+                );
+                (part_header, start, end)
+            }).collect();
+            let closing = format!("\r\n--{}--\r\n", boundary);
+            let content_length = parts.iter()
+                .map(|(part_header, start, end)| part_header.len() + end - start)
+                .sum::<usize>() + closing.len();
+
+            res.status(StatusCode::PARTIAL_CONTENT);
+            res.insert_header((
+                    header::CONTENT_TYPE,
+                    format!("multipart/byteranges; boundary={}", boundary)));
+            if is_head {
+                return Ok(res.body(SizedStream::new(content_length as u64, empty_body())));
+            }
+
+            let part_streams: Vec<_> = parts.into_iter().map(|(part_header, start, end)| {
+                stream::once(future::ready(Ok(Bytes::from(part_header))))
+                    .chain(streamer(start, end))
+            }).collect();
+            let body = stream::iter(part_streams)
+            .flatten()
+            .chain(stream::once(future::ready(Ok(Bytes::from(closing)))));
+            res.body(SizedStream::new(content_length as u64, body))
+        }
+    })
 }
 
 
 pub async fn run_server(
     devices_tx: UnboundedSender<ServerMessage>,
-    server_tx:  Sender<(ServerHandle, String, u16)>,
+    server_tx:  Sender<Result<(ServerHandle, String, u16), String>>,
     base_url:    Option<String>,
-    listen_port: Option<u16>
+    listen_port: Option<u16>,
+    load_timeout: Duration,
+    stream_signer: StreamSigner,
+    status_clients: ClientAllowList
     ) -> std::io::Result<()> {
     let server_internal = web::Data::new(
         Mutex::new(
-            ServerInternal { devices_tx }
+            ServerInternal { devices_tx, load_timeout, stream_signer, status_clients, last_modified: last_modified_now() }
         )
     );
     
     let server_url  = match base_url { Some(url) => url, _ => "0.0.0.0".to_string() };
     let server_port = match listen_port { Some(port) => port, _ => unused_addr().port() };
 
-    let server = HttpServer::new(move ||{
+    let server = match HttpServer::new(move ||{
         App::new()
             .app_data(server_internal.clone())
             .route("/hello", web::get().to(|| async { "Hello World!" }))
+            .service(greet)
+            .service(zones)
+            .service(zone)
+            .service(metrics_endpoint)
+            .service(transcode_stream) // Must come first, stream matches any track id
             .service(stream)
     })
-    .bind((server_url.clone(), server_port.clone()))?
-        .disable_signals()
-        .run();
+    .bind((server_url.clone(), server_port.clone())) {
+        Ok(server) => server.disable_signals().run(),
+        Err(e) => {
+            // Usually the port is taken or the address isn't local
+            let _ = server_tx.send(Err(format!("Could not listen on {}:{}: {}", server_url, server_port, e)));
+            return Err(e);
+        }
+    };
 
-    server_tx.send((server.handle(), server_url, server_port)).unwrap();
+    let _ = server_tx.send(Ok((server.handle(), server_url, server_port)));
     server.await
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test;
+    use actix_web::body::{BodySize, MessageBody};
+    use std::sync::Arc;
+    use std::thread;
+    use crate::transcode::tests::{flac_samples, silent_ogg_vorbis};
+
+    const ZONE_ID:   &str  = "zone";
+    const FILE_SIZE: usize = 100_000;
+
+    fn track_data(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    // Answers for every track from memory on its own thread, like a zone's player
+    // does. Returns the track ids of all reads in the order they were served.
+    fn fake_player(data: Vec<u8>, read_delay: Duration) -> (UnboundedSender<ServerMessage>, Arc<Mutex<Vec<String>>>) {
+        let (devices_tx, mut devices_rx) = mpsc::unbounded_channel::<ServerMessage>();
+        let reads     = Arc::new(Mutex::new(vec![]));
+        let reads_log = reads.clone();
+        thread::spawn(move || {
+            while let Some(msg) = devices_rx.blocking_recv() {
+                match msg {
+                    ServerMessage::TrackInfo { track_id, responder, .. } => {
+                        let _ = responder.send(ServerReply::TrackInfo {
+                            file_size: data.len(),
+                            format:    FileFormat::OGG_VORBIS_320,
+                            file_id:   FileId([7; 20]),
+                            track_id,
+                        });
+                    },
+                    ServerMessage::TrackRead { track_id, start, end, responder, .. } => {
+                        thread::sleep(read_delay);
+                        reads_log.lock().unwrap().push(track_id.clone());
+                        let end = end.min(data.len());
+                        let _ = responder.send(ServerReply::TrackRead {
+                            data:      data[start.min(end)..end].to_vec(),
+                            file_size: data.len(),
+                            track_id,
+                        });
+                    },
+                    ServerMessage::Zones { responder } => {
+                        let _ = responder.send(ServerReply::Zones(vec![]));
+                    },
+                    _ => ()
+                }
+            }
+        });
+        (devices_tx, reads)
+    }
+
+    fn server_data(devices_tx: UnboundedSender<ServerMessage>, stream_signer: StreamSigner) -> web::Data<Mutex<ServerInternal>> {
+        web::Data::new(Mutex::new(ServerInternal {
+            devices_tx,
+            load_timeout: Duration::from_secs(5),
+            stream_signer,
+            status_clients: ClientAllowList::parse(&["192.168.1.0/24"]).unwrap(),
+            last_modified: last_modified_now()
+        }))
+    }
+
+    fn range_request(signer: &StreamSigner, range: &str) -> test::TestRequest {
+        test::TestRequest::get()
+            .uri(&signer.stream_url(ZONE_ID, "track"))
+            .insert_header((header::RANGE, range.to_string()))
+    }
+
+    #[actix_web::test]
+    async fn serves_a_bounded_range() {
+        let data = track_data(FILE_SIZE);
+        let (devices_tx, _) = fake_player(data.clone(), Duration::ZERO);
+        let signer = StreamSigner::new();
+        let app = test::init_service(App::new().app_data(server_data(devices_tx, signer.clone())).service(stream)).await;
+
+        let res = test::call_service(&app, range_request(&signer, "bytes=100-40099").to_request()).await;
+        assert_eq!(res.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(res.headers().get(header::CONTENT_RANGE).unwrap(), "bytes 100-40099/100000");
+        assert_eq!(res.headers().get(header::CONTENT_TYPE).unwrap(), "audio/ogg");
+        assert_eq!(test::read_body(res).await, &data[100..40100]);
+    }
+
+    #[actix_web::test]
+    async fn serves_an_open_ended_range() {
+        let data = track_data(FILE_SIZE);
+        let (devices_tx, _) = fake_player(data.clone(), Duration::ZERO);
+        let signer = StreamSigner::new();
+        let app = test::init_service(App::new().app_data(server_data(devices_tx, signer.clone())).service(stream)).await;
+
+        let res = test::call_service(&app, range_request(&signer, "bytes=60000-").to_request()).await;
+        assert_eq!(res.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(res.headers().get(header::CONTENT_RANGE).unwrap(), "bytes 60000-99999/100000");
+        assert_eq!(test::read_body(res).await, &data[60000..]);
+    }
+
+    #[actix_web::test]
+    async fn serves_multiple_ranges_as_multipart() {
+        let data = track_data(FILE_SIZE);
+        let (devices_tx, _) = fake_player(data.clone(), Duration::ZERO);
+        let signer = StreamSigner::new();
+        let app = test::init_service(App::new().app_data(server_data(devices_tx, signer.clone())).service(stream)).await;
+
+        let res = test::call_service(&app, range_request(&signer, "bytes=0-9,50000-50009").to_request()).await;
+        assert_eq!(res.status(), StatusCode::PARTIAL_CONTENT);
+        let content_type = res.headers().get(header::CONTENT_TYPE).unwrap().to_str().unwrap().to_string();
+        let boundary = content_type
+            .strip_prefix("multipart/byteranges; boundary=")
+            .expect("multipart content type");
+
+        let mut expected = vec![];
+        for (start, end) in [(0, 10), (50000, 50010)] {
+            expected.extend_from_slice(format!(
+                "\r\n--{}\r\nContent-Type: audio/ogg\r\nContent-Range: bytes {}-{}/{}\r\n\r\n",
+                boundary, start, end - 1, FILE_SIZE
+            ).as_bytes());
+            expected.extend_from_slice(&data[start..end]);
+        }
+        expected.extend_from_slice(format!("\r\n--{}--\r\n", boundary).as_bytes());
+        assert_eq!(test::read_body(res).await, &expected[..]);
+    }
+
+    #[actix_web::test]
+    async fn rejects_an_unsatisfiable_range() {
+        let (devices_tx, reads) = fake_player(track_data(FILE_SIZE), Duration::ZERO);
+        let signer = StreamSigner::new();
+        let app = test::init_service(App::new().app_data(server_data(devices_tx, signer.clone())).service(stream)).await;
+
+        let res = test::call_service(&app, range_request(&signer, "bytes=200000-").to_request()).await;
+        assert_eq!(res.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(res.headers().get(header::CONTENT_RANGE).unwrap(), "bytes */100000");
+        assert!(reads.lock().unwrap().is_empty());
+    }
+
+    #[actix_web::test]
+    async fn head_sends_headers_only() {
+        let (devices_tx, reads) = fake_player(track_data(FILE_SIZE), Duration::ZERO);
+        let signer = StreamSigner::new();
+        let app = test::init_service(App::new().app_data(server_data(devices_tx, signer.clone())).service(stream)).await;
+
+        let req = test::TestRequest::default()
+            .method(Method::HEAD)
+            .uri(&signer.stream_url(ZONE_ID, "track"))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers().get(header::CONTENT_TYPE).unwrap(), "audio/ogg");
+        assert_eq!(res.headers().get(header::ACCEPT_RANGES).unwrap(), "bytes");
+        assert!(test::read_body(res).await.is_empty());
+        assert!(reads.lock().unwrap().is_empty());
+    }
+
+    // The player answers one read at a time from its own thread, many streams must
+    // still all progress instead of one finishing before the others start
+    #[actix_web::test]
+    async fn concurrent_streams_share_the_player() {
+        const STREAMS: usize = 32;
+        let data = track_data(8 * CHUNK_SIZE);
+        let (devices_tx, reads) = fake_player(data.clone(), Duration::from_millis(1));
+        let signer = StreamSigner::new();
+        let app = test::init_service(App::new().app_data(server_data(devices_tx, signer.clone())).service(stream)).await;
+
+        let track_ids: Vec<String> = (0..STREAMS).map(|i| format!("track{}", i)).collect();
+        let requests = track_ids.iter().map(|track_id| {
+            let req = test::TestRequest::get()
+                .uri(&signer.stream_url(ZONE_ID, track_id))
+                .to_request();
+            let app = &app;
+            async move { test::read_body(test::call_service(app, req).await).await }
+        });
+        let bodies = tokio::time::timeout(Duration::from_secs(30), future::join_all(requests))
+            .await
+            .expect("concurrent streams stalled");
+        for body in bodies {
+            assert_eq!(body, &data[..]);
+        }
+
+        // Every stream got its first chunk before any stream got its last one
+        let reads = reads.lock().unwrap();
+        assert_eq!(reads.len(), STREAMS * 8);
+        let latest_first = track_ids.iter()
+            .map(|id| reads.iter().position(|read| read == id).unwrap())
+            .max().unwrap();
+        let earliest_last = track_ids.iter()
+            .map(|id| reads.iter().rposition(|read| read == id).unwrap())
+            .min().unwrap();
+        assert!(latest_first < earliest_last, "a stream finished before another one started");
+    }
+
+    #[actix_web::test]
+    async fn status_and_metrics_are_only_served_to_allowed_clients() {
+        let (devices_tx, _) = fake_player(vec![], Duration::ZERO);
+        let app = test::init_service(App::new()
+            .app_data(server_data(devices_tx, StreamSigner::new()))
+            .service(zones)
+            .service(zone)
+            .service(metrics_endpoint)).await;
+
+        let local = test::TestRequest::get().uri("/zones").peer_addr("127.0.0.1:50000".parse().unwrap()).to_request();
+        let res = test::call_service(&app, local).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(test::read_body(res).await, "[]");
+
+        let allowed = test::TestRequest::get().uri("/zones").peer_addr("192.168.1.20:50000".parse().unwrap()).to_request();
+        assert_eq!(test::call_service(&app, allowed).await.status(), StatusCode::OK);
+
+        let allowed = test::TestRequest::get().uri("/metrics").peer_addr("192.168.1.20:50000".parse().unwrap()).to_request();
+        assert_eq!(test::call_service(&app, allowed).await.status(), StatusCode::OK);
+
+        for uri in ["/zones", "/zones/zone", "/metrics"] {
+            let denied = test::TestRequest::get().uri(uri).peer_addr("192.168.2.20:50000".parse().unwrap()).to_request();
+            assert_eq!(test::call_service(&app, denied).await.status(), StatusCode::FORBIDDEN, "{}", uri);
+
+            let unknown_peer = test::TestRequest::get().uri(uri).to_request();
+            assert_eq!(test::call_service(&app, unknown_peer).await.status(), StatusCode::FORBIDDEN, "{}", uri);
+        }
+    }
+
+    #[actix_web::test]
+    async fn validates_with_the_etag_and_last_modified() {
+        let (devices_tx, _) = fake_player(track_data(FILE_SIZE), Duration::ZERO);
+        let signer = StreamSigner::new();
+        let app = test::init_service(App::new().app_data(server_data(devices_tx, signer.clone())).service(stream)).await;
+        let etag = etag(&FileId([7; 20]), "");
+        let get = || test::TestRequest::get().uri(&signer.stream_url(ZONE_ID, "track"));
+
+        let res = test::call_service(&app, get().to_request()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let last_modified = res.headers().get(header::LAST_MODIFIED).unwrap().to_str().unwrap().to_string();
+        let earlier = HttpDate::from(http_date(&last_modified).unwrap() - Duration::from_secs(1)).to_string();
+
+        let req = get().insert_header((header::IF_NONE_MATCH, etag.clone())).to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(res.headers().get(header::LAST_MODIFIED).unwrap(), last_modified.as_str());
+
+        let req = get().insert_header((header::IF_MODIFIED_SINCE, last_modified.clone())).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::NOT_MODIFIED);
+
+        let req = get().insert_header((header::IF_MODIFIED_SINCE, earlier.clone())).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+
+        let req = get().insert_header((header::IF_MODIFIED_SINCE, "not a date")).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+
+        // A stale etag wins over a current date
+        let req = get()
+            .insert_header((header::IF_NONE_MATCH, "\"stale\""))
+            .insert_header((header::IF_MODIFIED_SINCE, last_modified.clone()))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+
+        for (if_range, status) in [(etag.as_str(), StatusCode::PARTIAL_CONTENT),
+                                   (last_modified.as_str(), StatusCode::PARTIAL_CONTENT),
+                                   (earlier.as_str(), StatusCode::OK),
+                                   ("\"stale\"", StatusCode::OK)] {
+            let req = range_request(&signer, "bytes=0-9").insert_header((header::IF_RANGE, if_range)).to_request();
+            assert_eq!(test::call_service(&app, req).await.status(), status, "{}", if_range);
+        }
+    }
+
+    fn transcode_url(signer: &StreamSigner, ext: &str) -> String {
+        signer.stream_url(ZONE_ID, "track").replacen("?", &format!(".{}?", ext), 1)
+    }
+
+    #[actix_web::test]
+    async fn transcodes_to_flac_in_chunks() {
+        let (devices_tx, _) = fake_player(silent_ogg_vorbis(2, 44100, 40), Duration::ZERO);
+        let signer = StreamSigner::new();
+        let app = test::init_service(App::new().app_data(server_data(devices_tx, signer.clone())).service(transcode_stream)).await;
+
+        let req = test::TestRequest::get().uri(&transcode_url(&signer, "flac")).to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers().get(header::CONTENT_TYPE).unwrap(), "audio/flac");
+        assert_eq!(res.headers().get(header::ACCEPT_RANGES).unwrap(), "none");
+        // No length up front, so the body goes out chunked
+        assert!(res.headers().get(header::CONTENT_LENGTH).is_none());
+        assert_eq!(res.response().body().size(), BodySize::Stream);
+
+        let (info, samples) = flac_samples(test::read_body(res).await.to_vec());
+        assert_eq!(info.channels, 2);
+        assert_eq!(samples.len(), 39 * 128 * 2);
+    }
+
+    #[actix_web::test]
+    async fn transcoding_ignores_ranges() {
+        let (devices_tx, _) = fake_player(silent_ogg_vorbis(2, 44100, 40), Duration::ZERO);
+        let signer = StreamSigner::new();
+        let app = test::init_service(App::new().app_data(server_data(devices_tx, signer.clone())).service(transcode_stream)).await;
+
+        let req = test::TestRequest::get()
+            .uri(&transcode_url(&signer, "wav"))
+            .insert_header((header::RANGE, "bytes=100-199"))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers().get(header::CONTENT_TYPE).unwrap(), "audio/wav");
+        assert!(res.headers().get(header::CONTENT_RANGE).is_none());
+        // The whole track, not the range
+        assert_eq!(test::read_body(res).await.len(), 44 + 39 * 128 * 2 * 2);
+    }
+
+    #[test]
+    fn content_type_follows_the_container() {
+        assert_eq!(content_type(FileFormat::OGG_VORBIS_320), "audio/ogg");
+        assert_eq!(content_type(FileFormat::MP3_320), "audio/mpeg");
+        for format in [FileFormat::AAC_160, FileFormat::AAC_320, FileFormat::MP4_128, FileFormat::MP4_128_DUAL] {
+            assert_eq!(content_type(format), "audio/mp4", "{:?}", format);
+        }
+    }
+}
@@ -0,0 +1,70 @@
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use hmac::{Hmac, Mac, NewMac};
+use rand::Rng;
+use sha1::Sha1;
+
+type HmacSha1 = Hmac<Sha1>;
+
+// Long enough for a queued podcast episode to be played to the end, Roon
+// keeps requesting ranges with the same url while it plays
+const STREAM_TOKEN_TTL: Duration = Duration::from_secs(6 * 60 * 60);
+
+// Signs stream urls so only urls we handed to Roon can be played. A new key is
+// made every time the host starts, which invalidates all older urls.
+#[derive(Clone)]
+pub struct StreamSigner {
+    key: Arc<[u8; 32]>,
+}
+
+impl StreamSigner {
+    pub fn new() -> StreamSigner {
+        StreamSigner {
+            key: Arc::new(rand::thread_rng().gen::<[u8; 32]>())
+        }
+    }
+
+    // Relative url, Roon is told the host and port on the javascript side
+    pub fn stream_url(&self, zone_id: &str, track_id: &str) -> String {
+        let expires = now_secs() + STREAM_TOKEN_TTL.as_secs();
+        format!("/stream/{}/{}?token={}", zone_id, track_id, self.token(zone_id, track_id, expires))
+    }
+
+    pub fn verify(&self, zone_id: &str, track_id: &str, token: &str) -> bool {
+        let (expires, signature) = match token.split_once('.') {
+            Some(parts) => parts,
+            None => return false
+        };
+        let expires = match expires.parse::<u64>() {
+            Ok(expires) => expires,
+            Err(_) => return false
+        };
+        let signature = match hex::decode(signature) {
+            Ok(signature) => signature,
+            Err(_) => return false
+        };
+        if expires < now_secs() {
+            return false;
+        }
+        // verify is constant time
+        self.mac(zone_id, track_id, expires).verify(&signature).is_ok()
+    }
+
+    fn token(&self, zone_id: &str, track_id: &str, expires: u64) -> String {
+        let signature = self.mac(zone_id, track_id, expires).finalize().into_bytes();
+        format!("{}.{}", expires, hex::encode(signature))
+    }
+
+    fn mac(&self, zone_id: &str, track_id: &str, expires: u64) -> HmacSha1 {
+        let mut mac = HmacSha1::new_from_slice(&self.key[..]).expect("HMAC takes keys of any size");
+        mac.update(format!("{}\n{}\n{}", zone_id, track_id, expires).as_bytes());
+        mac
+    }
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
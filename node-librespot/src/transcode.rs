@@ -0,0 +1,622 @@
+use std::io::{self, BufReader, Read, Seek, SeekFrom};
+use actix_web::web::Bytes;
+use byteorder::{BigEndian, LittleEndian, WriteBytesExt};
+use lewton::inside_ogg::OggStreamReader;
+use tokio::sync::mpsc::{Sender, UnboundedSender};
+use tokio::sync::oneshot;
+
+use librespot::metadata::FileFormat;
+use crate::server::{ServerMessage, ServerReply};
+use crate::formats;
+
+const READ_CHUNK_SIZE:  usize = 64 * 1024;
+const WRITE_CHUNK_SIZE: usize = 32 * 1024;
+const FLAC_BLOCK_SIZE:  usize = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TranscodeFormat {
+    Wav,
+    Flac
+}
+
+impl TranscodeFormat {
+    pub fn from_extension(ext: &str) -> Option<TranscodeFormat> {
+        match ext {
+            "wav"  => Some(TranscodeFormat::Wav),
+            "flac" => Some(TranscodeFormat::Flac),
+            _      => None
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            TranscodeFormat::Wav  => "audio/wav",
+            TranscodeFormat::Flac => "audio/flac"
+        }
+    }
+}
+
+// Only the Vorbis streams can be decoded, MP3 episodes are served as is
+pub fn can_transcode(format: FileFormat) -> bool {
+    formats::is_ogg_vorbis(format)
+}
+
+// Blocking Read + Seek over a track held by a zone's player. Every read is a
+// TrackRead round trip through the devices thread, so it must only be used off
+// the async runtime.
+pub struct TrackReader {
+    zone_id:    String,
+    track_id:   String,
+    file_size:  usize,
+    pos:        usize,
+    devices_tx: UnboundedSender<ServerMessage>,
+}
+
+impl TrackReader {
+    pub fn new(zone_id: String, track_id: String, file_size: usize, devices_tx: UnboundedSender<ServerMessage>) -> TrackReader {
+        TrackReader {
+            zone_id,
+            track_id,
+            file_size,
+            pos: 0,
+            devices_tx
+        }
+    }
+}
+
+impl Read for TrackReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.file_size || buf.is_empty() {
+            return Ok(0);
+        }
+        let end = self.file_size.min(self.pos + buf.len());
+        let (responder, receiver) = oneshot::channel::<ServerReply>();
+        self.devices_tx.send(ServerMessage::TrackRead {
+            zone_id:  self.zone_id.clone(),
+            track_id: self.track_id.clone(),
+            start:    self.pos,
+            end,
+            responder
+        }).map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "Devices thread shut down"))?;
+
+        match receiver.blocking_recv() {
+            Ok(ServerReply::TrackRead { data, .. }) => {
+                let len = data.len().min(buf.len());
+                buf[..len].copy_from_slice(&data[..len]);
+                self.pos += len;
+                Ok(len)
+            },
+            Ok(reply) => Err(io::Error::new(io::ErrorKind::Other, format!("Track read failed: {:?}", reply))),
+            Err(_err) => Err(io::Error::new(io::ErrorKind::BrokenPipe, "Player dropped chunk request"))
+        }
+    }
+}
+
+impl Seek for TrackReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let new_pos = match pos {
+            SeekFrom::Start(offset)   => offset as i64,
+            SeekFrom::End(offset)     => self.file_size as i64 + offset,
+            SeekFrom::Current(offset) => self.pos as i64 + offset,
+        };
+        if new_pos < 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Seek before start of track"));
+        }
+        self.pos = new_pos as usize;
+        Ok(self.pos as u64)
+    }
+}
+
+pub struct Transcoder {
+    stream:      OggStreamReader<BufReader<TrackReader>>,
+    format:      TranscodeFormat,
+    skip_frames: u64, // Decoded frames dropped before output starts
+}
+
+impl Transcoder {
+    // Reads the Vorbis headers, so a broken track is caught before the response
+    // status is sent
+    pub fn open(reader: TrackReader, format: TranscodeFormat, start_ms: Option<u32>) -> io::Result<Transcoder> {
+        let stream = OggStreamReader::new(BufReader::with_capacity(READ_CHUNK_SIZE, reader))
+            .map_err(vorbis_error)?;
+        // Ogg pages only give a coarse position, decoding from the start and
+        // dropping frames keeps the seek sample exact
+        let skip_frames = start_ms.unwrap_or(0) as u64 * stream.ident_hdr.audio_sample_rate as u64 / 1000;
+        Ok(Transcoder {
+            stream,
+            format,
+            skip_frames
+        })
+    }
+
+    // Decodes the whole track, handing encoded chunks to `out`. Returns early
+    // once the receiving side is gone.
+    pub fn run(mut self, out: &Sender<io::Result<Bytes>>) -> io::Result<()> {
+        let channels    = self.stream.ident_hdr.audio_channels as usize;
+        let sample_rate = self.stream.ident_hdr.audio_sample_rate;
+        let mut encoder = Encoder::new(self.format, channels, sample_rate)?;
+        let mut buf = encoder.header();
+
+        while let Some(samples) = self.stream.read_dec_packet_itl().map_err(vorbis_error)? {
+            let frames = (samples.len() / channels) as u64;
+            let skip   = frames.min(self.skip_frames);
+            self.skip_frames -= skip;
+            encoder.encode(&samples[skip as usize * channels..], &mut buf);
+            if buf.len() >= WRITE_CHUNK_SIZE
+                && out.blocking_send(Ok(Bytes::from(std::mem::take(&mut buf)))).is_err() {
+                return Ok(()); // Client went away
+            }
+        }
+        encoder.finish(&mut buf);
+        if !buf.is_empty() {
+            let _ = out.blocking_send(Ok(Bytes::from(buf)));
+        }
+        Ok(())
+    }
+}
+
+fn vorbis_error(err: lewton::VorbisError) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("{:?}", err))
+}
+
+enum Encoder {
+    Wav { channels: usize, sample_rate: u32 },
+    Flac(FlacEncoder)
+}
+
+impl Encoder {
+    fn new(format: TranscodeFormat, channels: usize, sample_rate: u32) -> io::Result<Encoder> {
+        if channels == 0 || channels > 8 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Unsupported channel count {}", channels)));
+        }
+        Ok(match format {
+            TranscodeFormat::Wav  => Encoder::Wav { channels, sample_rate },
+            TranscodeFormat::Flac => Encoder::Flac(FlacEncoder::new(channels, sample_rate))
+        })
+    }
+
+    fn header(&self) -> Vec<u8> {
+        match self {
+            Encoder::Wav { channels, sample_rate } => wav_header(*channels, *sample_rate),
+            Encoder::Flac(flac)                    => flac.header()
+        }
+    }
+
+    fn encode(&mut self, samples: &[i16], out: &mut Vec<u8>) {
+        match self {
+            Encoder::Wav { .. } => {
+                for sample in samples {
+                    out.write_i16::<LittleEndian>(*sample).unwrap();
+                }
+            },
+            Encoder::Flac(flac) => flac.encode(samples, out)
+        }
+    }
+
+    fn finish(&mut self, out: &mut Vec<u8>) {
+        if let Encoder::Flac(flac) = self {
+            flac.finish(out);
+        }
+    }
+}
+
+// The length isn't known up front, so the sizes are left at their maximum which
+// players treat as "until end of stream"
+fn wav_header(channels: usize, sample_rate: u32) -> Vec<u8> {
+    let block_align = channels as u16 * 2;
+    let mut header = Vec::with_capacity(44);
+    header.extend_from_slice(b"RIFF");
+    header.write_u32::<LittleEndian>(u32::MAX).unwrap();
+    header.extend_from_slice(b"WAVEfmt ");
+    header.write_u32::<LittleEndian>(16).unwrap();
+    header.write_u16::<LittleEndian>(1).unwrap(); // PCM
+    header.write_u16::<LittleEndian>(channels as u16).unwrap();
+    header.write_u32::<LittleEndian>(sample_rate).unwrap();
+    header.write_u32::<LittleEndian>(sample_rate * block_align as u32).unwrap();
+    header.write_u16::<LittleEndian>(block_align).unwrap();
+    header.write_u16::<LittleEndian>(16).unwrap();
+    header.extend_from_slice(b"data");
+    header.write_u32::<LittleEndian>(u32::MAX).unwrap();
+    header
+}
+
+// Minimal FLAC writer: fixed size blocks of 16 bit samples stored in verbatim
+// subframes. No compression, but every decoder can read it and it costs nothing.
+struct FlacEncoder {
+    channels:     usize,
+    sample_rate:  u32,
+    frame_number: u32,
+    pending:      Vec<i16>,
+}
+
+impl FlacEncoder {
+    fn new(channels: usize, sample_rate: u32) -> FlacEncoder {
+        FlacEncoder {
+            channels,
+            sample_rate,
+            frame_number: 0,
+            pending: Vec::with_capacity(FLAC_BLOCK_SIZE * channels)
+        }
+    }
+
+    // Marker plus a STREAMINFO block with unknown total samples, frame sizes and MD5
+    fn header(&self) -> Vec<u8> {
+        let mut header = Vec::with_capacity(42);
+        header.extend_from_slice(b"fLaC");
+        header.push(0x80); // Last metadata block, STREAMINFO
+        header.write_u24::<BigEndian>(34).unwrap();
+        header.write_u16::<BigEndian>(FLAC_BLOCK_SIZE as u16).unwrap();
+        header.write_u16::<BigEndian>(FLAC_BLOCK_SIZE as u16).unwrap();
+        header.write_u24::<BigEndian>(0).unwrap();
+        header.write_u24::<BigEndian>(0).unwrap();
+        let info = (self.sample_rate as u64) << 44
+            | ((self.channels as u64 - 1) << 41)
+            | (15 << 36); // 16 bits per sample, total samples left at 0
+        header.write_u64::<BigEndian>(info).unwrap();
+        header.extend_from_slice(&[0u8; 16]);
+        header
+    }
+
+    fn encode(&mut self, samples: &[i16], out: &mut Vec<u8>) {
+        let block_len = FLAC_BLOCK_SIZE * self.channels;
+        for sample in samples {
+            self.pending.push(*sample);
+            if self.pending.len() == block_len {
+                self.write_frame(out);
+            }
+        }
+    }
+
+    // Last frame of a fixed blocksize stream is allowed to be short
+    fn finish(&mut self, out: &mut Vec<u8>) {
+        if !self.pending.is_empty() {
+            self.write_frame(out);
+        }
+    }
+
+    fn write_frame(&mut self, out: &mut Vec<u8>) {
+        let block_size = self.pending.len() / self.channels;
+        let frame_start = out.len();
+
+        out.extend_from_slice(&[0xff, 0xf8]); // Sync code, fixed blocksize
+        out.push(0x70); // Blocksize in a trailing 16 bit field, rate from STREAMINFO
+        out.push((((self.channels - 1) as u8) << 4) | 0x08); // Independent channels, 16 bit
+        write_utf8_number(out, self.frame_number);
+        out.write_u16::<BigEndian>((block_size - 1) as u16).unwrap();
+        let header_crc = crc8(&out[frame_start..]);
+        out.push(header_crc);
+
+        for channel in 0..self.channels {
+            out.push(0x02); // Verbatim subframe, no wasted bits
+            for frame in self.pending.chunks_exact(self.channels) {
+                out.write_i16::<BigEndian>(frame[channel]).unwrap();
+            }
+        }
+        let frame_crc = crc16(&out[frame_start..]);
+        out.write_u16::<BigEndian>(frame_crc).unwrap();
+
+        self.pending.clear();
+        self.frame_number += 1;
+    }
+}
+
+fn write_utf8_number(out: &mut Vec<u8>, n: u32) {
+    if n < 0x80 {
+        out.push(n as u8);
+        return;
+    }
+    let (len, prefix) = match n {
+        0..=0x7ff            => (2, 0xc0),
+        0x800..=0xffff       => (3, 0xe0),
+        0x10000..=0x1fffff   => (4, 0xf0),
+        0x200000..=0x3ffffff => (5, 0xf8),
+        _                    => (6, 0xfc)
+    };
+    out.push(prefix | (n >> (6 * (len - 1))) as u8);
+    for i in (0..len - 1).rev() {
+        out.push(0x80 | ((n >> (6 * i)) & 0x3f) as u8);
+    }
+}
+
+fn crc8(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |mut crc, byte| {
+        crc ^= byte;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 { (crc << 1) ^ 0x07 } else { crc << 1 };
+        }
+        crc
+    })
+}
+
+fn crc16(data: &[u8]) -> u16 {
+    data.iter().fold(0u16, |mut crc, byte| {
+        crc ^= (*byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 { (crc << 1) ^ 0x8005 } else { crc << 1 };
+        }
+        crc
+    })
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use std::thread;
+    use tokio::sync::mpsc;
+
+    // Vorbis packs header fields least significant bit first
+    struct BitWriter {
+        bytes: Vec<u8>,
+        bit:   u32,
+    }
+
+    impl BitWriter {
+        fn new() -> BitWriter {
+            BitWriter { bytes: vec![], bit: 0 }
+        }
+
+        fn write(&mut self, value: u32, bits: u32) {
+            for i in 0..bits {
+                if self.bit == 0 {
+                    self.bytes.push(0);
+                }
+                *self.bytes.last_mut().unwrap() |= (((value >> i) & 1) as u8) << self.bit;
+                self.bit = (self.bit + 1) % 8;
+            }
+        }
+    }
+
+    fn ogg_crc(data: &[u8]) -> u32 {
+        data.iter().fold(0u32, |mut crc, byte| {
+            crc ^= (*byte as u32) << 24;
+            for _ in 0..8 {
+                crc = if crc & 0x8000_0000 != 0 { (crc << 1) ^ 0x04c1_1db7 } else { crc << 1 };
+            }
+            crc
+        })
+    }
+
+    fn ogg_page(out: &mut Vec<u8>, header_type: u8, granule: u64, sequence: u32, packets: &[Vec<u8>]) {
+        let mut lacing = vec![];
+        for packet in packets {
+            lacing.resize(lacing.len() + packet.len() / 255, 255u8);
+            lacing.push((packet.len() % 255) as u8);
+        }
+        let start = out.len();
+        out.extend_from_slice(b"OggS");
+        out.push(0); // Version
+        out.push(header_type);
+        out.write_u64::<LittleEndian>(granule).unwrap();
+        out.write_u32::<LittleEndian>(1).unwrap(); // Serial
+        out.write_u32::<LittleEndian>(sequence).unwrap();
+        out.write_u32::<LittleEndian>(0).unwrap(); // Checksum, filled in below
+        out.push(lacing.len() as u8);
+        out.extend_from_slice(&lacing);
+        for packet in packets {
+            out.extend_from_slice(packet);
+        }
+        let crc = ogg_crc(&out[start..]);
+        out[start + 22..start + 26].copy_from_slice(&crc.to_le_bytes());
+    }
+
+    // Smallest valid Vorbis stream: one codebook, floor, residue, mapping and mode,
+    // and audio packets that mark every channel unused. Decodes to 128 frames of
+    // silence per packet after the first.
+    pub(crate) fn silent_ogg_vorbis(channels: u8, sample_rate: u32, audio_packets: usize) -> Vec<u8> {
+        let mut ident = b"\x01vorbis".to_vec();
+        ident.write_u32::<LittleEndian>(0).unwrap();
+        ident.push(channels);
+        ident.write_u32::<LittleEndian>(sample_rate).unwrap();
+        ident.extend_from_slice(&[0u8; 12]); // Bitrates
+        ident.push(0x88); // 256 sample blocks
+        ident.push(1);
+
+        let mut comment = b"\x03vorbis".to_vec();
+        comment.extend_from_slice(&[0u8; 8]); // No vendor, no comments
+        comment.push(1);
+
+        let mut setup = BitWriter::new();
+        for byte in b"\x05vorbis" {
+            setup.write(*byte as u32, 8);
+        }
+        let fields: &[(u32, u32)] = &[
+            (0, 8),         // One codebook
+            (0x564342, 24), // Codebook sync
+            (1, 16),        // Dimensions
+            (2, 24),        // Entries
+            (0, 1),         // Not ordered
+            (0, 1),         // Not sparse
+            (0, 5),         // Both entries one bit long
+            (0, 5),
+            (0, 4),         // No lookup table
+            (0, 6),         // One time domain transform
+            (0, 16),
+            (0, 6),         // One floor
+            (1, 16),        // Floor type 1
+            (0, 5),         // No partitions
+            (0, 2),         // Multiplier 1
+            (8, 4),         // Range bits
+            (0, 6),         // One residue
+            (0, 16),        // Residue type 0
+            (0, 24),        // Begin
+            (0, 24),        // End
+            (0, 24),        // Partition size 1
+            (0, 6),         // One classification
+            (0, 8),         // Classbook
+            (0, 3),         // Cascade
+            (0, 1),
+            (0, 6),         // One mapping
+            (0, 16),        // Mapping type 0
+            (0, 1),         // One submap
+            (0, 1),         // No coupling
+            (0, 2),         // Reserved
+            (0, 8),         // Submap time, floor and residue
+            (0, 8),
+            (0, 8),
+            (0, 6),         // One mode
+            (0, 1),         // Short blocks
+            (0, 16),        // Window type
+            (0, 16),        // Transform type
+            (0, 8),         // Mapping
+            (1, 1),         // Framing
+        ];
+        for (value, bits) in fields {
+            setup.write(*value, *bits);
+        }
+
+        let mut audio = BitWriter::new();
+        audio.write(0, 1); // Audio packet
+        for _ in 0..channels {
+            audio.write(0, 1); // Floor unused
+        }
+
+        let mut out = vec![];
+        ogg_page(&mut out, 0x02, 0, 0, &[ident]);
+        ogg_page(&mut out, 0x00, 0, 1, &[comment, setup.bytes]);
+        let packets = vec![audio.bytes; audio_packets];
+        ogg_page(&mut out, 0x04, (audio_packets as u64 - 1) * 128, 2, &packets);
+        out
+    }
+
+    // Answers TrackRead from memory like a zone's player
+    fn reader(data: Vec<u8>) -> TrackReader {
+        let (devices_tx, mut devices_rx) = mpsc::unbounded_channel::<ServerMessage>();
+        let file_size = data.len();
+        thread::spawn(move || {
+            while let Some(msg) = devices_rx.blocking_recv() {
+                if let ServerMessage::TrackRead { track_id, start, end, responder, .. } = msg {
+                    let _ = responder.send(ServerReply::TrackRead {
+                        data:      data[start.min(data.len())..end.min(data.len())].to_vec(),
+                        file_size: data.len(),
+                        track_id,
+                    });
+                }
+            }
+        });
+        TrackReader::new("zone".to_string(), "track".to_string(), file_size, devices_tx)
+    }
+
+    fn transcode(data: Vec<u8>, format: TranscodeFormat, start_ms: Option<u32>) -> Vec<u8> {
+        let transcoder = Transcoder::open(reader(data), format, start_ms).unwrap();
+        let (out_tx, mut out_rx) = mpsc::channel(16);
+        let handle = thread::spawn(move || transcoder.run(&out_tx));
+        let mut out = vec![];
+        while let Some(chunk) = out_rx.blocking_recv() {
+            out.extend_from_slice(&chunk.unwrap());
+        }
+        handle.join().unwrap().unwrap();
+        out
+    }
+
+    pub(crate) fn flac_samples(data: Vec<u8>) -> (claxon::metadata::StreamInfo, Vec<i32>) {
+        let mut reader = claxon::FlacReader::new(Cursor::new(data)).unwrap();
+        let info = reader.streaminfo();
+        let samples = reader.samples().map(|s| s.unwrap()).collect();
+        (info, samples)
+    }
+
+    #[test]
+    fn wav_header_describes_16_bit_pcm() {
+        assert_eq!(wav_header(2, 44100), [
+            b'R', b'I', b'F', b'F', 0xff, 0xff, 0xff, 0xff,
+            b'W', b'A', b'V', b'E', b'f', b'm', b't', b' ',
+            16, 0, 0, 0,         // fmt chunk size
+            1, 0,                // PCM
+            2, 0,                // Channels
+            0x44, 0xac, 0, 0,    // 44100 Hz
+            0x10, 0xb1, 0x02, 0, // Bytes per second
+            4, 0,                // Block align
+            16, 0,               // Bits per sample
+            b'd', b'a', b't', b'a', 0xff, 0xff, 0xff, 0xff,
+        ]);
+    }
+
+    #[test]
+    fn flac_header_holds_streaminfo() {
+        let mut expected = vec![
+            b'f', b'L', b'a', b'C',
+            0x80, 0, 0, 34,                     // Last block, STREAMINFO, 34 bytes
+            0x10, 0x00, 0x10, 0x00,             // Min and max block size 4096
+            0, 0, 0, 0, 0, 0,                   // Frame sizes unknown
+            0x0a, 0xc4, 0x42, 0xf0, 0, 0, 0, 0, // 44100 Hz, 2 channels, 16 bit, length unknown
+        ];
+        expected.extend_from_slice(&[0u8; 16]); // MD5 unknown
+        assert_eq!(FlacEncoder::new(2, 44100).header(), expected);
+    }
+
+    #[test]
+    fn crcs_match_the_check_values() {
+        // CRC-8 and CRC-16/BUYPASS as used by FLAC frames
+        assert_eq!(crc8(b"123456789"), 0xf4);
+        assert_eq!(crc16(b"123456789"), 0xfee8);
+    }
+
+    #[test]
+    fn frame_numbers_are_utf8_coded() {
+        let coded = |n| {
+            let mut out = vec![];
+            write_utf8_number(&mut out, n);
+            out
+        };
+        assert_eq!(coded(0), [0x00]);
+        assert_eq!(coded(0x7f), [0x7f]);
+        assert_eq!(coded(0x80), [0xc2, 0x80]);
+        assert_eq!(coded(0x7ff), [0xdf, 0xbf]);
+        assert_eq!(coded(0x800), [0xe0, 0xa0, 0x80]);
+        assert_eq!(coded(0xffff), [0xef, 0xbf, 0xbf]);
+        assert_eq!(coded(0x10000), [0xf0, 0x90, 0x80, 0x80]);
+    }
+
+    #[test]
+    fn flac_frame_matches_known_bytes() {
+        let mut flac = FlacEncoder::new(1, 44100);
+        let mut out = vec![];
+        flac.encode(&[1, -1], &mut out);
+        flac.finish(&mut out);
+        assert_eq!(out, [
+            0xff, 0xf8, 0x70, 0x08,       // Sync, trailing block size, mono 16 bit
+            0x00,                         // Frame 0
+            0x00, 0x01,                   // Block size 2
+            0x1d,                         // Header CRC-8
+            0x02, 0x00, 0x01, 0xff, 0xff, // Verbatim subframe
+            0xf4, 0xf2,                   // Frame CRC-16
+        ]);
+    }
+
+    #[test]
+    fn flac_round_trips_through_a_decoder() {
+        // Two full blocks and a short one
+        let samples: Vec<i16> = (0..(FLAC_BLOCK_SIZE * 2 + 100) * 2)
+            .map(|i| (i * 7919 % 65536) as u16 as i16)
+            .collect();
+        let mut flac = FlacEncoder::new(2, 48000);
+        let mut out = flac.header();
+        flac.encode(&samples, &mut out);
+        flac.finish(&mut out);
+
+        let (info, decoded) = flac_samples(out);
+        assert_eq!(info.sample_rate, 48000);
+        assert_eq!(info.channels, 2);
+        assert_eq!(info.bits_per_sample, 16);
+        assert_eq!(decoded, samples.iter().map(|s| *s as i32).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn transcodes_vorbis_to_flac() {
+        let flac = transcode(silent_ogg_vorbis(2, 44100, 40), TranscodeFormat::Flac, None);
+        let (info, samples) = flac_samples(flac);
+        assert_eq!(info.sample_rate, 44100);
+        assert_eq!(info.channels, 2);
+        assert_eq!(samples.len(), 39 * 128 * 2);
+        assert!(samples.iter().all(|s| *s == 0));
+    }
+
+    #[test]
+    fn transcodes_vorbis_to_wav_from_a_position() {
+        // 1ms at 44.1kHz drops 44 frames
+        let wav = transcode(silent_ogg_vorbis(2, 44100, 40), TranscodeFormat::Wav, Some(1));
+        assert_eq!(wav[..44], wav_header(2, 44100)[..]);
+        assert_eq!(wav.len() - 44, (39 * 128 - 44) * 2 * 2);
+    }
+}
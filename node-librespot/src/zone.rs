@@ -1,29 +1,81 @@
 use simplelog::*;
 use sha1::{Digest, Sha1};
-use tokio::sync::mpsc::{UnboundedSender};
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+use tokio::sync::oneshot;
 
+use librespot::core::authentication::Credentials;
+use librespot::core::cache::Cache;
 use librespot::core::config::{ConnectConfig, DeviceType, SessionConfig};
 use librespot::discovery::{Discovery};
-use librespot::playback::config::{PlayerConfig};
+use librespot::playback::config::{Bitrate, PlayerConfig};
 use librespot::connect::spirc::Spirc;
 use librespot::core::session::Session;
 use librespot::playback::mixer::{self, MixerConfig};
-use librespot::metadata::{AudioItem};
+use librespot::metadata::{AudioItem, FileFormat};
+use librespot::core::spotify_id::SpotifyId;
+use librespot::protocol::metadata::{Track, Episode, Show, Image, Date};
 
 // Custom player
 use futures_util::{future, FutureExt, StreamExt};
+use futures::future::BoxFuture;
+use futures_core::Stream;
 use std::time::Duration;
 use std::pin::Pin;
 use std::time::Instant;
-use std::process::exit;
 use crate::player::{Player};
+use crate::playerinternal::{PreloadTimer};
 use std::sync::{Arc, Mutex};
 
 
 use serde::{Serialize, Deserialize};
-use crate::server::{ServerMessage};
+use crate::server::{ServerMessage, ServerReply};
+use crate::cache::{AudioCacheLimiter, CacheConfig};
+use crate::stream_token::{StreamSigner};
+use crate::metrics;
 
 
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RoonImage {
+    pub file_id: String,
+    pub size:    String, // DEFAULT, SMALL, LARGE or XLARGE
+    pub width:   i32,
+    pub height:  i32
+}
+
+impl RoonImage {
+    fn from_images(images: &[Image]) -> Option<Vec<RoonImage>> {
+        let images: Vec<RoonImage> = images.iter().map(|image| RoonImage {
+            file_id: hex::encode(image.get_file_id()),
+            size:    format!("{:?}", image.get_size()),
+            width:   image.get_width(),
+            height:  image.get_height()
+        }).collect();
+        if images.is_empty() { None } else { Some(images) }
+    }
+}
+
+// Spotify's loudness data for the track, album gain when playing as album
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RoonReplayGain {
+    pub gain_db: f32,
+    pub peak:    f32,
+    pub album:   bool
+}
+
+fn format_date(date: &Date) -> String {
+    match (date.get_month(), date.get_day()) {
+        (0, _) => format!("{:04}", date.get_year()),
+        (m, 0) => format!("{:04}-{:02}", date.get_year(), m),
+        (m, d) => format!("{:04}-{:02}-{:02}", date.get_year(), m, d)
+    }
+}
+
+fn gid_to_uri(kind: &str, gid: &[u8]) -> Option<String> {
+    let id = SpotifyId::from_raw(gid).ok()?;
+    Some(format!("spotify:{}:{}", kind, id.to_base62().ok()?))
+}
+
+// Fields after show_name are optional so older JS callbacks keep working
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct RoonNowPlaying {
     pub track_id:   String,
@@ -31,7 +83,33 @@ pub struct RoonNowPlaying {
     pub album_name: Option<String>,
     pub artists:    Option<Vec<String>>,
     pub covers:     Option<Vec<String>>,
-    pub show_name:  Option<String>
+    pub show_name:  Option<String>,
+    #[serde(default)]
+    pub duration_ms:   Option<u32>,
+    #[serde(default)]
+    pub track_number:  Option<i32>,
+    #[serde(default)]
+    pub disc_number:   Option<i32>,
+    #[serde(default)]
+    pub album_artists: Option<Vec<String>>,
+    #[serde(default)]
+    pub release_year:  Option<i32>,
+    #[serde(default)]
+    pub explicit:      Option<bool>,
+    #[serde(default)]
+    pub artist_uris:   Option<Vec<String>>,
+    #[serde(default)]
+    pub album_uri:     Option<String>,
+    #[serde(default)]
+    pub images:        Option<Vec<RoonImage>>,
+    #[serde(default)]
+    pub media_type:    Option<String>, // "track" or "episode"
+    #[serde(default)]
+    pub publisher:     Option<String>,
+    #[serde(default)]
+    pub description:   Option<String>,
+    #[serde(default)]
+    pub release_date:  Option<String>  // YYYY-MM-DD, or less when unknown
 }
 impl RoonNowPlaying {
     pub fn new(audio: AudioItem) -> RoonNowPlaying {
@@ -50,21 +128,100 @@ impl RoonNowPlaying {
             show_name= Some(show.name);
         }
 
+        // Hex ids only, sizes are in images
         let mut covers = None;
         if let Some(_covers) = audio.covers.clone() {
-            // XXX Make this an object to include sizes
             covers= Some(_covers.iter().map(|f| f.to_base16().unwrap()).collect());
         }
 
+        let mut duration_ms = None;
+        if audio.duration >= 0 {
+            duration_ms = Some(audio.duration as u32);
+        }
+
         RoonNowPlaying {
             track_id:  audio.id.to_uri().unwrap(),
             name:      audio.name.clone(),
             album_name,
             artists,
             show_name,
-            covers
+            covers,
+            duration_ms,
+            track_number:  None,
+            disc_number:   None,
+            album_artists: None,
+            release_year:  None,
+            explicit:      None,
+            artist_uris:   None,
+            album_uri:     None,
+            images:        None,
+            media_type:    None,
+            publisher:     None,
+            description:   None,
+            release_date:  None
+        }
+    }
+
+    // Fields AudioItem doesn't carry, from the raw track metadata
+    pub fn add_track_metadata(&mut self, track: &Track) {
+        let album = track.get_album();
+        self.media_type   = Some("track".to_string());
+        self.track_number = Some(track.get_number());
+        self.disc_number  = Some(track.get_disc_number());
+        self.explicit     = Some(track.get_explicit());
+        if album.has_date() {
+            self.release_year = Some(album.get_date().get_year());
+            self.release_date = Some(format_date(album.get_date()));
+        }
+
+        let album_artists: Vec<String> = album.get_artist().iter().map(|a| a.get_name().to_string()).collect();
+        if !album_artists.is_empty() {
+            self.album_artists = Some(album_artists);
         }
+        self.artist_uris = Some(track.get_artist().iter().filter_map(|a| gid_to_uri("artist", a.get_gid())).collect());
+        self.album_uri   = gid_to_uri("album", album.get_gid());
+
+        self.images = RoonImage::from_images(album.get_cover_group().get_image())
+            .or_else(|| RoonImage::from_images(album.get_cover()));
     }
+
+    pub fn add_episode_metadata(&mut self, episode: &Episode) {
+        self.media_type = Some("episode".to_string());
+        self.explicit   = Some(episode.get_explicit());
+        if episode.has_publish_time() {
+            self.release_year = Some(episode.get_publish_time().get_year());
+            self.release_date = Some(format_date(episode.get_publish_time()));
+        }
+        if episode.has_description() {
+            self.description = Some(episode.get_description().to_string());
+        }
+        self.images = RoonImage::from_images(episode.get_covers().get_image());
+    }
+
+    // Episodes without their own artwork fall back to the show's
+    pub fn add_show_metadata(&mut self, show: &Show) {
+        if self.show_name.is_none() && show.has_name() {
+            self.show_name = Some(show.get_name().to_string());
+        }
+        if show.has_publisher() {
+            self.publisher = Some(show.get_publisher().to_string());
+        }
+        if self.images.is_none() {
+            self.images = RoonImage::from_images(show.get_covers().get_image());
+        }
+        if self.covers.as_ref().map_or(true, |covers| covers.is_empty()) {
+            self.covers = self.images.as_ref().map(|images| images.iter().map(|i| i.file_id.clone()).collect());
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum ZoneErrorKind {
+    Discovery,
+    Connection,
+    Mixer,
+    Reconnect,
+    Player
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -75,7 +232,10 @@ pub enum SpotifyJSEvent {
         now_playing_info: RoonNowPlaying,
         position_ms:      u32,
         play_request_id:  u64,
-        preload_id:       Option<u64>
+        preload_id:       Option<u64>,
+        stream_url:       String, // Signed, relative to the http server
+        #[serde(default)]
+        replay_gain:      Option<RoonReplayGain>
     },
     Unpause {
         zone_id: String,
@@ -93,7 +253,10 @@ pub enum SpotifyJSEvent {
     Preload {
         zone_id:     String,
         now_playing_info: RoonNowPlaying,
-        preload_id:       u64
+        preload_id:       u64,
+        stream_url:       String,
+        #[serde(default)]
+        replay_gain:      Option<RoonReplayGain>
     },
     Clear {
         zone_id: String,
@@ -102,6 +265,16 @@ pub enum SpotifyJSEvent {
     VolumeSet {
         zone_id: String,
         volume:  u16 // 64k value
+    },
+    Reconnected {
+        zone_id: String,
+        attempt: usize
+    },
+    ZoneError {
+        zone_id:     String,
+        kind:        ZoneErrorKind,
+        message:     String,
+        recoverable: bool // Zone restarts itself after a backoff
     }
 }
 
@@ -110,7 +283,13 @@ pub enum SpotifyJSEvent {
 pub enum RoonMessage {
     EnableZone {
         name: String,
-        id:   String
+        id:   String,
+        #[serde(default)]
+        bitrate: Option<u16>, // kbps, 96, 160 or 320
+        #[serde(default)]
+        formats: Option<Vec<String>>, // In order of preference, e.g. ["MP3_320", "OGG_VORBIS_320"]
+        #[serde(default)]
+        preload_lead_ms: Option<u32> // Preload the next track at least this long before the end
     },
     DisableZone {
         id: String
@@ -122,86 +301,345 @@ pub enum RoonMessage {
     Playing             { id: String },
     Paused              { id: String },
     Unpaused            { id: String },
-    Seeked              { id: String },
     NextTrack           { id: String },
     PreviousTrack       { id: String },
     Stopped             { id: String },
     EndedNaturally      { id: String },
     OnToNext            { id: String },
-    Error               { id: String },
     Volume {
         id: String ,
         volume: u16
     },
+    Error {
+        id:     String,
+        #[serde(default)]
+        reason: String,
+        #[serde(default = "default_error_slot")]
+        slot:   String // "play" or "queue", whichever roon failed to play
+    },
+    SetBitrate {
+        id:      String,
+        bitrate: u16 // kbps, applied from the next loaded track
+    },
     Time {
         id:               String,
         seek_position_ms: u32,
         track_id:         String
     },
+    Seeked {
+        id:               String,
+        seek_position_ms: u32,
+        #[serde(default)]
+        track_id:         Option<String> // Seeks for any other track are stale
+    },
+}
+
+fn default_error_slot() -> String {
+    "play".to_string()
 }
 
 fn device_id(name: &str) -> String {
     hex::encode(Sha1::digest(name.as_bytes()))
 }
 
-fn start_discovery(name: String, device_id: String) -> Option<Discovery> {
-    match librespot::discovery::Discovery::builder(device_id.clone())
-        .name(name.clone())
-        .device_type(librespot::discovery::DeviceType::Computer)
-        .launch()
-        {
-            Ok(d) => return Some(d),
-            Err(err) => {
-                warn!("Could not initialize disovery: {}.", err);
-                return None;
+pub fn bitrate_kbps(bitrate: Bitrate) -> u16 {
+    match bitrate {
+        Bitrate::Bitrate96  => 96,
+        Bitrate::Bitrate160 => 160,
+        Bitrate::Bitrate320 => 320,
+    }
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ConnectionState {
+    Idle,
+    Discovering,
+    Connecting,
+    Connected,
+    Restarting
+}
+
+// Snapshot served by /zones, the zone fills in the connection and the player
+// the rest
+#[derive(Serialize, Debug, Clone)]
+pub struct ZoneStatus {
+    pub zone_id:          String,
+    pub name:             String,
+    pub connection:       ConnectionState,
+    pub discovery:        bool,
+    pub user:             Option<String>,
+    pub bitrate:          u16, // kbps
+    pub restart_attempts: u32,
+    pub last_error:       Option<String>, // Filled in by devices, which sees every ZoneError
+    pub player:           Option<PlayerStatus>
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct PlayerStatus {
+    pub state:            String,
+    pub track_id:         Option<String>,
+    pub now_playing:      Option<RoonNowPlaying>,
+    pub position_ms:      Option<u32>,
+    pub duration_ms:      Option<u32>,
+    pub preload:          String,
+    pub preload_track_id: Option<String>,
+    pub volume:           Option<u16>, // 64k value, last one spotify set
+    pub recent_tracks:    usize,
+    pub parked_requests:  usize,
+    pub preload_timing:   Option<PreloadTiming>
+}
+
+// How far before the end of the current track the next one gets preloaded
+#[derive(Serialize, Debug, Clone)]
+pub struct PreloadTiming {
+    pub throughput_bytes_per_sec: Option<u64>, // Unknown until a download has been timed
+    pub remaining_bytes:          u64, // Still to download for the current track
+    pub min_lead_ms:              u32,
+    pub lead_ms:                  u32,
+    pub suggested_at_ms:          Option<u32>  // Time left when the preload was suggested
+}
+
+pub fn bitrate_from_kbps(kbps: u16) -> Option<Bitrate> {
+    match kbps {
+        96  => Some(Bitrate::Bitrate96),
+        160 => Some(Bitrate::Bitrate160),
+        320 => Some(Bitrate::Bitrate320),
+        _   => {
+            warn!("Unsupported bitrate {}, expected 96, 160 or 320", kbps);
+            None
+        }
+    }
+}
+
+// What a zone needs to create its player once a session is up
+pub struct PlayerSetup {
+    pub config:              PlayerConfig,
+    pub server_rx:           Arc<Mutex<UnboundedReceiver<ServerMessage>>>,
+    pub roon_rx:             Arc<Mutex<UnboundedReceiver<RoonMessage>>>,
+    pub js_tx:               Arc<Mutex<UnboundedSender<SpotifyJSEvent>>>,
+    pub stream_signer:       StreamSigner,
+    pub formats:             Vec<FileFormat>,
+    pub audio_cache_limiter: Option<Arc<AudioCacheLimiter>>,
+    pub preload_timer:       Arc<Mutex<PreloadTimer>>, // Shared by every player of the zone
+    pub zone_id:             String,
+}
+
+pub trait ZoneSpirc: Send {
+    fn shutdown(&self);
+    fn rename(&self, name: String);
+}
+
+impl ZoneSpirc for Spirc {
+    fn shutdown(&self) {
+        Spirc::shutdown(self)
+    }
+
+    fn rename(&self, name: String) {
+        Spirc::rename(self, name)
+    }
+}
+
+// Discovery, sessions and spirc for a zone, tests drive the zone with a fake one
+pub trait SpotifyConnector: Send + 'static {
+    type Session: Send + 'static;
+    type Spirc: ZoneSpirc;
+    type Discovery: Stream<Item = Credentials> + Unpin + Send;
+
+    fn discover(&self, name: String, device_id: String) -> Option<Self::Discovery>;
+    fn connect(&self, config: SessionConfig, credentials: Credentials, cache: Option<Cache>)
+        -> BoxFuture<'static, Result<Self::Session, String>>;
+    fn username(session: &Self::Session) -> String;
+    // Fails when no mixer could be created
+    fn start(&self, session: Self::Session, connect_config: ConnectConfig, player: PlayerSetup)
+        -> Result<(Self::Spirc, BoxFuture<'static, ()>), String>;
+}
+
+pub struct LibrespotConnector;
+
+impl SpotifyConnector for LibrespotConnector {
+    type Session   = Session;
+    type Spirc     = Spirc;
+    type Discovery = Discovery;
+
+    fn discover(&self, name: String, device_id: String) -> Option<Discovery> {
+        match librespot::discovery::Discovery::builder(device_id)
+            .name(name)
+            .device_type(librespot::discovery::DeviceType::Computer)
+            .launch()
+            {
+                Ok(d) => Some(d),
+                Err(err) => {
+                    warn!("Could not initialize disovery: {}.", err);
+                    None
+                }
             }
+    }
+
+    fn connect(&self, config: SessionConfig, credentials: Credentials, cache: Option<Cache>)
+        -> BoxFuture<'static, Result<Session, String>> {
+        Box::pin(Session::connect(config, credentials, cache, true)
+            .map(|session| session
+                .map(|(session, _)| session)
+                .map_err(|e| e.to_string())))
+    }
+
+    fn username(session: &Session) -> String {
+        session.username()
+    }
+
+    fn start(&self, session: Session, connect_config: ConnectConfig, player: PlayerSetup)
+        -> Result<(Spirc, BoxFuture<'static, ()>), String> {
+        let mixer = match mixer::find(None) {
+            Some(mixer) => mixer(MixerConfig::default()),
+            None => return Err("Creating mixer failed".to_string())
+        };
+        let (player, _event_channel) = Player::new(
+            player.config,
+            session.clone(),
+            player.server_rx,
+            player.roon_rx,
+            player.js_tx,
+            player.stream_signer,
+            player.formats,
+            player.audio_cache_limiter,
+            player.preload_timer,
+            player.zone_id
+        );
+        let (spirc, spirc_task) = Spirc::new(connect_config, session, player, mixer);
+        Ok((spirc, Box::pin(spirc_task)))
+    }
+}
+
+const RESTART_BACKOFF_BASE: Duration = Duration::from_secs(5);
+const RESTART_BACKOFF_MAX:  Duration = Duration::from_secs(300);
+
+fn restart_backoff(attempt: u32) -> Duration {
+    RESTART_BACKOFF_BASE
+        .checked_mul(1 << attempt.min(6))
+        .unwrap_or(RESTART_BACKOFF_MAX)
+        .min(RESTART_BACKOFF_MAX)
+}
+
+const RECONNECT_RATE_LIMIT_WINDOW: Duration = Duration::from_secs(600);
+// Reconnects allowed in the window on top of the first, as in librespot's main.rs
+const RECONNECT_RATE_LIMIT: usize = 5;
+
+// Automatic reconnects after spirc shuts down, stops a zone from hammering spotify
+#[derive(Default)]
+struct ReconnectLimiter {
+    attempts: Vec<Instant>,
+}
+
+impl ReconnectLimiter {
+    // The attempt number if another reconnect fits in the window
+    fn try_reconnect(&mut self, now: Instant) -> Option<usize> {
+        self.attempts.retain(|&t| now.saturating_duration_since(t) < RECONNECT_RATE_LIMIT_WINDOW);
+        if self.attempts.len() > RECONNECT_RATE_LIMIT {
+            return None;
         }
+        self.attempts.push(now);
+        Some(self.attempts.len())
+    }
+
+    fn clear(&mut self) {
+        self.attempts.clear();
+    }
+}
+
+fn send_zone_error(
+    js_tx: &Arc<Mutex<UnboundedSender<SpotifyJSEvent>>>,
+    zone_id: &str,
+    kind: ZoneErrorKind,
+    message: String,
+    recoverable: bool
+) {
+    error!("Zone {} {:?} error: {}", zone_id, kind, message);
+    let _ = js_tx.lock().unwrap().send(SpotifyJSEvent::ZoneError {
+        zone_id: zone_id.to_string(),
+        kind,
+        message,
+        recoverable
+    });
 }
 
 pub struct Zone {
     commands:       UnboundedSender<RoonMessage>,
     server_player_tx: UnboundedSender<ServerMessage>,
     roon_player_tx: UnboundedSender<RoonMessage>,
+    status_tx:      UnboundedSender<oneshot::Sender<ServerReply>>,
 }
 
 impl Zone {
-    pub fn new(name: String, id: String, js_tx: UnboundedSender<SpotifyJSEvent>) -> Zone {
+    pub fn new(name: String, id: String, bitrate: Option<u16>, formats: Vec<FileFormat>, preload_lead_ms: Option<u32>, cache_config: CacheConfig, stream_signer: StreamSigner, js_tx: UnboundedSender<SpotifyJSEvent>) -> Zone {
+        Zone::start(LibrespotConnector, name, id, bitrate, formats, preload_lead_ms, cache_config, stream_signer, js_tx)
+    }
+
+    fn start<C: SpotifyConnector>(connector: C, name: String, id: String, bitrate: Option<u16>, formats: Vec<FileFormat>, preload_lead_ms: Option<u32>, cache_config: CacheConfig, stream_signer: StreamSigner, js_tx: UnboundedSender<SpotifyJSEvent>) -> Zone {
         let (commands_tx,      mut commands_rx)  = tokio::sync::mpsc::unbounded_channel();
         let (server_player_tx, player_server_rx) = tokio::sync::mpsc::unbounded_channel();
         let (roon_player_tx,   player_roon_rx)   = tokio::sync::mpsc::unbounded_channel();
+        let (status_tx,        mut status_rx)    = tokio::sync::mpsc::unbounded_channel::<oneshot::Sender<ServerReply>>();
+        let status_player_tx                     = server_player_tx.clone();
 
         let player_roon_arc   = Arc::new(Mutex::new(player_roon_rx));
         let player_server_arc = Arc::new(Mutex::new(player_server_rx));
         let js_callback_tx    = Arc::new(Mutex::new(js_tx));
+        let preload_timer     = Arc::new(Mutex::new(PreloadTimer::new(preload_lead_ms)));
 
         tokio::spawn(async move {
-            const RECONNECT_RATE_LIMIT_WINDOW: Duration = Duration::from_secs(600);
-            const RECONNECT_RATE_LIMIT: usize = 5;
-
             let mut last_credentials = None;
-            let mut spirc: Option<Spirc> = None;
-            let mut spirc_task: Option<Pin<_>> = None;
-            let mut auto_connect_times: Vec<Instant> = vec![];
-            let mut discovery = None;
-            let mut connecting: Pin<Box<dyn future::FusedFuture<Output = _> + Send>> = Box::pin(future::pending());
+            let mut spirc: Option<C::Spirc> = None;
+            let mut spirc_task: Option<BoxFuture<'static, ()>> = None;
+            let mut reconnects = ReconnectLimiter::default();
+            // Reported to js once the reconnect actually succeeded
+            let mut pending_reconnect: Option<usize> = None;
+            let mut discovery: Option<C::Discovery> = None;
+            let mut connecting: Pin<Box<dyn future::FusedFuture<Output = Result<C::Session, String>> + Send>> = Box::pin(future::pending());
+
+            // Failures tear down the session and schedule a restart of this zone only
+            let mut restart_at: Option<Instant> = None;
+            let mut restart_attempts: u32 = 0;
+            // Discovery is restarted on its own, a running spirc keeps playing
+            let mut discovery_restart_at: Option<Instant> = None;
+            let mut discovery_attempts: u32 = 0;
+            // Nothing brings a zone back from this but disabling and enabling it
+            let mut unrecoverable = false;
+            let mut connected_user: Option<String> = None;
 
-            let player_config  = PlayerConfig::default();
+            let mut player_config = PlayerConfig::default();
+            if let Some(bitrate) = bitrate.and_then(bitrate_from_kbps) {
+                player_config.bitrate = bitrate;
+            }
             let session_config = SessionConfig {
                 user_agent: String::from("FOOBARBUZZ"),
                 device_id: device_id(&id),
                 proxy:     None,
                 ap_port:   None
             };
+            let cache          = cache_config.zone_cache(&session_config.device_id);
+            let initial_volume = cache.as_ref().and_then(|c| c.volume()).or(Some(50));
             let mut connect_config = ConnectConfig {
                 name:            name.clone(),
                 device_type:     DeviceType::default(),
-                initial_volume:  Some(50),
+                initial_volume,
                 has_volume_ctrl: true,
                 autoplay:        false,
             };
             info!("Starting discovery: {},{}",connect_config.name.clone(),session_config.device_id.clone());
 
-            discovery = start_discovery(name.clone(), session_config.device_id.clone());
+            discovery = connector.discover(name.clone(), session_config.device_id.clone());
+
+            // Reconnect right away if this zone was connected before a restart
+            if let Some(credentials) = cache.as_ref().and_then(|c| c.credentials()) {
+                info!("Found cached credentials for zone {}, connecting", name.clone());
+                last_credentials = Some(credentials.clone());
+                connecting = Box::pin(connector.connect(
+                        session_config.clone(),
+                        credentials,
+                        cache.clone()
+                        ).fuse());
+            }
 
             // Port from librespot main.rs
             loop {
@@ -218,21 +656,59 @@ impl Zone {
                                     connect_config = ConnectConfig {
                                         name:            rename_to.clone(),
                                         device_type:     DeviceType::default(),
-                                        initial_volume:  Some(50),
+                                        initial_volume,
                                         has_volume_ctrl: true,
                                         autoplay:        false,
                                     };
-                                    discovery = start_discovery(rename_to.clone(), session_config.device_id.clone());
+                                    if !unrecoverable {
+                                        discovery = connector.discover(rename_to.clone(), session_config.device_id.clone());
+                                    }
                                     if let Some(s) = spirc {
                                         s.rename(rename_to);
                                         spirc = Some(s);
                                     }
                                 },
+                                RoonMessage::SetBitrate { bitrate, .. } => {
+                                    // Player picks this up itself, keep it for reconnects
+                                    if let Some(bitrate) = bitrate_from_kbps(bitrate) {
+                                        player_config.bitrate = bitrate;
+                                    }
+                                },
                                 _ => ()
                             },
                             _ => break
                         }
                     },
+                    Some(responder) = status_rx.recv() => {
+                        let connection = if restart_at.is_some() {
+                            ConnectionState::Restarting
+                        } else if spirc.is_some() {
+                            ConnectionState::Connected
+                        } else if !connecting.is_terminated() {
+                            ConnectionState::Connecting
+                        } else if discovery.is_some() {
+                            ConnectionState::Discovering
+                        } else {
+                            ConnectionState::Idle
+                        };
+                        let status = ZoneStatus {
+                            zone_id:          id.clone(),
+                            name:             connect_config.name.clone(),
+                            connection,
+                            discovery:        discovery.is_some(),
+                            user:             connected_user.clone().filter(|_| spirc.is_some()),
+                            bitrate:          bitrate_kbps(player_config.bitrate),
+                            restart_attempts,
+                            last_error:       None,
+                            player:           None
+                        };
+                        // Only a running player reads its server channel
+                        if spirc.is_some() {
+                            let _ = status_player_tx.send(ServerMessage::PlayerStatus { zone: status, responder });
+                        } else {
+                            let _ = responder.send(ServerReply::ZoneStatus(status));
+                        }
+                    },
                     credentials = async {
                         match discovery.as_mut() {
                             Some(d) => d.next().await,
@@ -242,7 +718,10 @@ impl Zone {
                         match credentials {
                             Some(credentials) => {
                                 last_credentials = Some(credentials.clone());
-                                auto_connect_times.clear();
+                                reconnects.clear();
+                                pending_reconnect = None;
+                                restart_attempts = 0;
+                                restart_at       = None;
 
                                 if let Some(spirc) = spirc.take() {
                                     spirc.shutdown();
@@ -252,42 +731,66 @@ impl Zone {
                                     tokio::spawn(spirc_task);
                                 }
 
-                                connecting = Box::pin(Session::connect(
+                                connecting = Box::pin(connector.connect(
                                         session_config.clone(),
                                         credentials,
-                                        None,//Cache
-                                        true,
+                                        cache.clone()
                                         ).fuse());
                             },
                             None => {
-                                error!("Discovery stopped unexpectedly");
-                                exit(1);
+                                discovery = None;
+                                send_zone_error(&js_callback_tx, &id, ZoneErrorKind::Discovery,
+                                    "Discovery stopped unexpectedly".to_string(), true);
+                                discovery_restart_at = Some(Instant::now() + restart_backoff(discovery_attempts));
+                                discovery_attempts += 1;
                             }
                         }
                     },
                     session = &mut connecting, if !connecting.is_terminated() => match session {
-                        Ok((session,_)) => {
-                            let mixer_config = MixerConfig::default();
-                            let mixer = mixer::find(None).unwrap_or_else(|| {
-                                info!("CREATING MIXER FIALED");
-                                exit(1);
-                            })(mixer_config);
-                            let (player, _event_channel) = Player::new(
-                                player_config.clone(),
-                                session.clone(),
-                                player_server_arc.clone(),
-                                player_roon_arc.clone(),
-                                js_callback_tx.clone(),
-                                id.clone()
-                            );
+                        Ok(session) => {
+                            connected_user = Some(C::username(&session));
+                            let player = PlayerSetup {
+                                config:              player_config.clone(),
+                                server_rx:           player_server_arc.clone(),
+                                roon_rx:             player_roon_arc.clone(),
+                                js_tx:               js_callback_tx.clone(),
+                                stream_signer:       stream_signer.clone(),
+                                formats:             formats.clone(),
+                                audio_cache_limiter: cache_config.audio_limiter.clone(),
+                                preload_timer:       preload_timer.clone(),
+                                zone_id:             id.clone()
+                            };
+                            let (spirc_, spirc_task_) = match connector.start(session, connect_config.clone(), player) {
+                                Ok(started) => started,
+                                Err(message) => {
+                                    // No mixer backend compiled in, restarting won't help so stop
+                                    // connecting until the zone is enabled again
+                                    send_zone_error(&js_callback_tx, &id, ZoneErrorKind::Mixer, message, false);
+                                    unrecoverable        = true;
+                                    discovery            = None;
+                                    discovery_restart_at = None;
+                                    last_credentials     = None;
+                                    pending_reconnect    = None;
+                                    continue;
+                                }
+                            };
                             info!("CREATED NEW SPIRC FOR ZONE {}", name.clone());
-                            let (spirc_, spirc_task_) = Spirc::new(connect_config.clone(), session, player, mixer);
                             spirc      = Some(spirc_);
-                            spirc_task = Some(Box::pin(spirc_task_));
+                            spirc_task = Some(spirc_task_);
+                            restart_attempts = 0;
+                            if let Some(attempt) = pending_reconnect.take() {
+                                let _ = js_callback_tx.lock().unwrap().send(SpotifyJSEvent::Reconnected {
+                                    zone_id: id.clone(),
+                                    attempt
+                                });
+                            }
                         },
                         Err(e) => {
-                            error!("Connection failed: {}", e);
-                            exit(1);
+                            pending_reconnect = None;
+                            send_zone_error(&js_callback_tx, &id, ZoneErrorKind::Connection,
+                                format!("Connection failed: {}", e), true);
+                            restart_at = Some(Instant::now() + restart_backoff(restart_attempts));
+                            restart_attempts += 1;
                         }
                     },
                     _ = async {
@@ -299,28 +802,86 @@ impl Zone {
 
                         warn!("Spirc shut down unexpectedly");
 
-                        let mut reconnect_exceeds_rate_limit = || {
-                            auto_connect_times.retain(|&t| t.elapsed() < RECONNECT_RATE_LIMIT_WINDOW);
-                            auto_connect_times.len() > RECONNECT_RATE_LIMIT
+                        let attempt = match last_credentials {
+                            Some(_) => reconnects.try_reconnect(Instant::now()),
+                            None    => None
                         };
+                        match (last_credentials.clone(), attempt) {
+                            (Some(credentials), Some(attempt)) => {
+                                metrics::record_reconnect_attempt();
+                                info!("Reconnecting zone {}, attempt {}", connect_config.name.clone(), attempt);
+                                pending_reconnect = Some(attempt);
 
-                        match last_credentials.clone() {
-                            Some(credentials) if !reconnect_exceeds_rate_limit() => {
-                                auto_connect_times.push(Instant::now());
-
-                                connecting = Box::pin(Session::connect(
-                                        SessionConfig::default(),
+                                // Same device id and user agent so spotify sees the same device
+                                connecting = Box::pin(connector.connect(
+                                        session_config.clone(),
                                         credentials,
-                                        None,//Cache
-                                        true
+                                        cache.clone()
                                         ).fuse());
                             },
                             _ => {
-                                error!("Spirc shut down too often.  Not reconnecting automatically.");
-                                exit(1);
+                                send_zone_error(&js_callback_tx, &id, ZoneErrorKind::Reconnect,
+                                    "Spirc shut down too often.  Not reconnecting automatically.".to_string(), true);
+                                restart_at = Some(Instant::now() + restart_backoff(restart_attempts));
+                                restart_attempts += 1;
                             },
                         }
                     },
+                    _ = async {
+                        if let Some(at) = restart_at {
+                            tokio::time::sleep_until(at.into()).await;
+                        }
+                    }, if restart_at.is_some() => {
+                        restart_at = None;
+                        metrics::record_reconnect_attempt();
+                        info!("Restarting zone {}, attempt {}", connect_config.name.clone(), restart_attempts);
+
+                        if let Some(spirc) = spirc.take() {
+                            spirc.shutdown();
+                        }
+                        if let Some(spirc_task) = spirc_task.take() {
+                            tokio::spawn(spirc_task);
+                        }
+                        reconnects.clear();
+                        pending_reconnect = None;
+
+                        if discovery.is_none() {
+                            discovery = connector.discover(connect_config.name.clone(), session_config.device_id.clone());
+                        }
+                        match last_credentials.clone() {
+                            Some(credentials) => {
+                                connecting = Box::pin(connector.connect(
+                                        session_config.clone(),
+                                        credentials,
+                                        cache.clone()
+                                        ).fuse());
+                            },
+                            None if discovery.is_none() => {
+                                send_zone_error(&js_callback_tx, &id, ZoneErrorKind::Discovery,
+                                    "Could not restart discovery".to_string(), true);
+                                discovery_restart_at = Some(Instant::now() + restart_backoff(discovery_attempts));
+                                discovery_attempts += 1;
+                            },
+                            None => ()
+                        }
+                    },
+                    _ = async {
+                        if let Some(at) = discovery_restart_at {
+                            tokio::time::sleep_until(at.into()).await;
+                        }
+                    }, if discovery_restart_at.is_some() => {
+                        discovery_restart_at = None;
+                        info!("Restarting discovery for zone {}, attempt {}", connect_config.name.clone(), discovery_attempts);
+                        discovery = connector.discover(connect_config.name.clone(), session_config.device_id.clone());
+                        if discovery.is_some() {
+                            discovery_attempts = 0;
+                        } else {
+                            send_zone_error(&js_callback_tx, &id, ZoneErrorKind::Discovery,
+                                "Could not restart discovery".to_string(), true);
+                            discovery_restart_at = Some(Instant::now() + restart_backoff(discovery_attempts));
+                            discovery_attempts += 1;
+                        }
+                    },
                 }
             }
 
@@ -332,6 +893,7 @@ impl Zone {
         Zone {
             server_player_tx,
             roon_player_tx, 
+            status_tx,
             commands: commands_tx,
         }
     }
@@ -343,4 +905,192 @@ impl Zone {
     pub fn send_server_message(&mut self, msg: ServerMessage) {
         self.server_player_tx.send(msg).unwrap();
     }
+
+    // Dropping the responder tells the server this zone went away
+    pub fn send_status_request(&mut self, responder: oneshot::Sender<ServerReply>) {
+        let _ = self.status_tx.send(responder);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::mpsc::{self, error::TryRecvError};
+    use tokio_stream::wrappers::UnboundedReceiverStream;
+
+    // Attempts allowed in a single window
+    const ALLOWED: usize = RECONNECT_RATE_LIMIT + 1;
+
+    #[test]
+    fn reconnects_up_to_the_limit_within_the_window() {
+        let mut limiter = ReconnectLimiter::default();
+        let start = Instant::now();
+        for attempt in 1..=ALLOWED {
+            let at = start + Duration::from_secs(attempt as u64);
+            assert_eq!(limiter.try_reconnect(at), Some(attempt));
+        }
+        let at = start + Duration::from_secs(ALLOWED as u64 + 1);
+        assert_eq!(limiter.try_reconnect(at), None);
+    }
+
+    #[test]
+    fn refused_reconnects_are_not_counted() {
+        let mut limiter = ReconnectLimiter::default();
+        let start = Instant::now();
+        for _ in 0..ALLOWED {
+            limiter.try_reconnect(start);
+        }
+        assert_eq!(limiter.try_reconnect(start), None);
+        assert_eq!(limiter.try_reconnect(start), None);
+        assert_eq!(limiter.attempts.len(), ALLOWED);
+    }
+
+    #[test]
+    fn attempts_expire_at_the_end_of_the_window() {
+        let mut limiter = ReconnectLimiter::default();
+        let start = Instant::now();
+        for _ in 0..ALLOWED {
+            limiter.try_reconnect(start);
+        }
+        let just_inside = start + RECONNECT_RATE_LIMIT_WINDOW - Duration::from_millis(1);
+        assert_eq!(limiter.try_reconnect(just_inside), None);
+        // All of the first attempts are exactly one window old now
+        let boundary = start + RECONNECT_RATE_LIMIT_WINDOW;
+        assert_eq!(limiter.try_reconnect(boundary), Some(1));
+    }
+
+    #[test]
+    fn clear_resets_the_window() {
+        let mut limiter = ReconnectLimiter::default();
+        let start = Instant::now();
+        for _ in 0..ALLOWED {
+            limiter.try_reconnect(start);
+        }
+        limiter.clear();
+        assert_eq!(limiter.try_reconnect(start), Some(1));
+    }
+
+    type ConnectRequest = (SessionConfig, oneshot::Sender<Result<String, String>>);
+
+    struct FakeSpirc;
+
+    impl ZoneSpirc for FakeSpirc {
+        fn shutdown(&self) {}
+        fn rename(&self, _name: String) {}
+    }
+
+    // Hands every connect and spirc start to the test, which decides how they end
+    struct FakeConnector {
+        discovery: Mutex<Option<UnboundedReceiverStream<Credentials>>>,
+        connects:  UnboundedSender<ConnectRequest>,
+        spircs:    UnboundedSender<oneshot::Sender<()>>,
+    }
+
+    impl SpotifyConnector for FakeConnector {
+        type Session   = String; // user name
+        type Spirc     = FakeSpirc;
+        type Discovery = UnboundedReceiverStream<Credentials>;
+
+        fn discover(&self, _name: String, _device_id: String) -> Option<Self::Discovery> {
+            self.discovery.lock().unwrap().take()
+        }
+
+        fn connect(&self, config: SessionConfig, _credentials: Credentials, _cache: Option<Cache>)
+            -> BoxFuture<'static, Result<String, String>> {
+            let (result_tx, result_rx) = oneshot::channel();
+            let _ = self.connects.send((config, result_tx));
+            Box::pin(async move {
+                result_rx.await.unwrap_or_else(|_| Err("Test dropped the connect".to_string()))
+            })
+        }
+
+        fn username(session: &String) -> String {
+            session.clone()
+        }
+
+        fn start(&self, _session: String, _connect_config: ConnectConfig, _player: PlayerSetup)
+            -> Result<(FakeSpirc, BoxFuture<'static, ()>), String> {
+            // Spirc runs until the test shuts it down
+            let (shutdown_tx, shutdown_rx) = oneshot::channel();
+            let _ = self.spircs.send(shutdown_tx);
+            Ok((FakeSpirc, Box::pin(async move {
+                let _ = shutdown_rx.await;
+            })))
+        }
+    }
+
+    struct Harness {
+        _zone:       Zone,
+        credentials: UnboundedSender<Credentials>,
+        connects:    UnboundedReceiver<ConnectRequest>,
+        spircs:      UnboundedReceiver<oneshot::Sender<()>>,
+        js_rx:       UnboundedReceiver<SpotifyJSEvent>,
+    }
+
+    fn start_zone() -> Harness {
+        let (credentials, credentials_rx) = mpsc::unbounded_channel();
+        let (connects_tx, connects)       = mpsc::unbounded_channel();
+        let (spircs_tx, spircs)           = mpsc::unbounded_channel();
+        let (js_tx, js_rx)                = mpsc::unbounded_channel();
+        let connector = FakeConnector {
+            discovery: Mutex::new(Some(UnboundedReceiverStream::new(credentials_rx))),
+            connects:  connects_tx,
+            spircs:    spircs_tx,
+        };
+        let zone = Zone::start(connector, "Zone".to_string(), "zone".to_string(), None, vec![], None,
+            CacheConfig::default(), StreamSigner::new(), js_tx);
+        Harness { _zone: zone, credentials, connects, spircs, js_rx }
+    }
+
+    async fn next<T>(rx: &mut UnboundedReceiver<T>) -> T {
+        tokio::time::timeout(Duration::from_secs(5), rx.recv()).await
+            .expect("Timed out waiting on the zone")
+            .expect("Zone went away")
+    }
+
+    // Connects through discovery and returns the session config and the running spirc
+    async fn connect(harness: &mut Harness) -> (SessionConfig, oneshot::Sender<()>) {
+        harness.credentials.send(Credentials::with_password("user", "password")).unwrap();
+        let (config, result) = next(&mut harness.connects).await;
+        result.send(Ok("user".to_string())).unwrap();
+        (config, next(&mut harness.spircs).await)
+    }
+
+    #[tokio::test]
+    async fn reconnects_with_the_same_session_config() {
+        let mut harness = start_zone();
+        let (config, spirc) = connect(&mut harness).await;
+
+        spirc.send(()).unwrap();
+        let (reconnect_config, result) = next(&mut harness.connects).await;
+        assert_eq!(reconnect_config.device_id, config.device_id);
+        assert_eq!(reconnect_config.user_agent, config.user_agent);
+        // Not reconnected until the session is up
+        assert!(matches!(harness.js_rx.try_recv(), Err(TryRecvError::Empty)));
+
+        result.send(Ok("user".to_string())).unwrap();
+        let _restarted = next(&mut harness.spircs).await;
+        match next(&mut harness.js_rx).await {
+            SpotifyJSEvent::Reconnected { zone_id, attempt } => {
+                assert_eq!(zone_id, "zone");
+                assert_eq!(attempt, 1);
+            },
+            event => panic!("Expected Reconnected, got {:?}", event)
+        }
+    }
+
+    #[tokio::test]
+    async fn failed_reconnects_are_not_reported_as_reconnected() {
+        let mut harness = start_zone();
+        let (_, spirc) = connect(&mut harness).await;
+
+        spirc.send(()).unwrap();
+        let (_, result) = next(&mut harness.connects).await;
+        result.send(Err("Connection refused".to_string())).unwrap();
+        match next(&mut harness.js_rx).await {
+            SpotifyJSEvent::ZoneError { kind: ZoneErrorKind::Connection, recoverable: true, .. } => (),
+            event => panic!("Expected a connection error, got {:?}", event)
+        }
+        assert!(matches!(harness.js_rx.try_recv(), Err(TryRecvError::Empty)));
+    }
 }